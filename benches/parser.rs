@@ -1,23 +1,74 @@
-#![allow(dead_code, unused_imports)]
+use std::hint::black_box;
 
 use criterion::{Criterion, criterion_group, criterion_main};
 
-#[path = "../src/segment.rs"]
-mod segment;
-
-#[path = "../src/decode/mod.rs"]
-mod decode;
-
 fn parse_mummyforced(c: &mut Criterion) {
     let bytes = std::fs::read("data/mummyforced.sup").expect("read data/mummyforced.sup");
 
     c.bench_function("parse_frames/mummyforced", |b| {
         b.iter(|| {
-            let frames = decode::parse_frames(&bytes).expect("parse mummyforced.sup");
-            criterion::black_box(frames);
+            let frames = sup_decode::parse_frames(&bytes).expect("parse mummyforced.sup");
+            black_box(frames);
+        });
+    });
+}
+
+fn parse_and_decode(c: &mut Criterion) {
+    let bytes = std::fs::read("data/mummyforced.sup").expect("read data/mummyforced.sup");
+
+    // compare with the parallel run below: `cargo bench --features parallel`; both parse the
+    // stream and decode every bitmap into a cache that holds all of them
+    c.bench_function("parse_and_decode/mummyforced", |b| {
+        b.iter(|| {
+            let frames = sup_decode::parse_frames(&bytes).expect("parse mummyforced.sup");
+            let objects = frames.iter().flat_map(|frame| &frame.objects);
+            let bitmaps = sup_decode::BitmapCache::new(objects.clone().count());
+
+            for object in objects {
+                bitmaps.get(object);
+            }
+
+            black_box((frames, bitmaps));
+        });
+    });
+
+    #[cfg(feature = "parallel")]
+    c.bench_function("parse_and_decode_parallel/mummyforced", |b| {
+        b.iter(|| {
+            let frames = sup_decode::parse_frames_parallel(&bytes).expect("parse mummyforced.sup");
+            let bitmaps = sup_decode::decode_bitmaps_parallel(&frames);
+            black_box((frames, bitmaps));
+        });
+    });
+}
+
+fn decode_bitmaps(c: &mut Criterion) {
+    let bytes = std::fs::read("data/mummyforced.sup").expect("read data/mummyforced.sup");
+    let frames = sup_decode::parse_frames(&bytes).expect("parse mummyforced.sup");
+    let objects = frames.iter().flat_map(|frame| &frame.objects);
+    let pixel_count = |object: &sup_decode::decode::object::Object| {
+        usize::from(object.width) * usize::from(object.height)
+    };
+
+    let mut buf = vec![
+        0;
+        objects
+            .clone()
+            .map(|object| pixel_count(object))
+            .max()
+            .unwrap_or(0)
+    ];
+
+    c.bench_function("decode_bitmaps/mummyforced", |b| {
+        b.iter(|| {
+            for object in objects.clone() {
+                object.decode_into(&mut buf[..pixel_count(object)]);
+            }
+
+            black_box(&buf);
         });
     });
 }
 
-criterion_group!(benches, parse_mummyforced);
+criterion_group!(benches, parse_mummyforced, parse_and_decode, decode_bitmaps);
 criterion_main!(benches);
@@ -0,0 +1,217 @@
+//! Checks shared by the fuzz targets and by `tests/fuzz.rs`, which runs them on generated inputs
+//! under plain `cargo test`.
+//!
+//! Every check panics if the decoder panics, allocates much more than its input justifies, or
+//! disagrees with the encoder.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+};
+
+use sup_decode::{
+    RleMode,
+    decode::{
+        object::Object,
+        ods::{ObjectFragment, SequenceFlag},
+    },
+    encode::{encode_rle, encode_segment, write_display_sets},
+    ts,
+};
+
+/// Most pixels a strictly valid RLE stream can describe per byte: a 65535-pixel line of color 0
+/// takes four 3-byte codes, one 2-byte code and the end-of-line marker.
+const MAX_PIXELS_PER_RLE_BYTE: usize = 4096;
+
+/// Largest bitmap decoded in lenient mode, which pads missing lines whatever the data holds.
+const MAX_LENIENT_PIXELS: usize = 1 << 22;
+
+/// Allocator that tracks the peak number of bytes live on each thread.
+pub struct TrackingAlloc;
+
+thread_local! {
+    static LIVE: Cell<isize> = const { Cell::new(0) };
+    static PEAK: Cell<isize> = const { Cell::new(0) };
+}
+
+fn track(delta: isize) {
+    // the thread locals are gone while a thread shuts down
+    let _ = LIVE.try_with(|live| {
+        let now = live.get() + delta;
+        live.set(now);
+        PEAK.with(|peak| peak.set(peak.get().max(now)));
+    });
+}
+
+unsafe impl GlobalAlloc for TrackingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        track(layout.size() as isize);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        track(layout.size() as isize);
+        unsafe { System.alloc_zeroed(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        track(-(layout.size() as isize));
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        track(new_size as isize - layout.size() as isize);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+/// Runs `f`, returning its output and the most bytes it had allocated at any one time on this
+/// thread. Only meaningful in binaries using [`TrackingAlloc`] as their global allocator.
+fn peak_allocation<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    let start = LIVE.with(Cell::get);
+    PEAK.with(|peak| peak.set(start));
+
+    let output = f();
+
+    (output, (PEAK.with(Cell::get) - start) as usize)
+}
+
+/// Allocation allowed for decoding `len` bytes of PGS data.
+///
+/// Display sets copy the windows and palette of their epoch, so a stream of tiny display sets
+/// sharing a large palette legitimately costs a few dozen times its size.
+fn stream_allowance(len: usize) -> usize {
+    128 * len + (64 << 10)
+}
+
+/// Parses one segment, and checks that it encodes back to the bytes it was parsed from.
+pub fn parse_segment(data: &[u8]) {
+    let mut input = data;
+    let (segment, peak) = peak_allocation(|| sup_decode::parse_segment(&mut input));
+    assert!(
+        peak <= stream_allowance(data.len()),
+        "allocated {peak} bytes"
+    );
+
+    let Ok(segment) = segment else {
+        assert_eq!(data.len(), input.len(), "input advanced on error");
+        return;
+    };
+
+    let mut written = Vec::new();
+    encode_segment(&segment, &mut written).unwrap();
+    assert_eq!(&data[..data.len() - input.len()], written);
+}
+
+/// Parses a stream strictly and leniently, and checks that strictly parsed display sets survive
+/// being written out and parsed again.
+pub fn parse_frames(data: &[u8]) {
+    let (frames, peak) = peak_allocation(|| sup_decode::parse_frames(data));
+    assert!(
+        peak <= stream_allowance(data.len()),
+        "allocated {peak} bytes"
+    );
+
+    let (_, peak) = peak_allocation(|| sup_decode::parse_frames_lenient(data));
+    assert!(
+        peak <= stream_allowance(data.len()),
+        "allocated {peak} bytes"
+    );
+
+    let Ok(frames) = frames else {
+        return;
+    };
+
+    let mut written = Vec::new();
+    write_display_sets(&mut written, &frames).unwrap();
+    assert_eq!(frames, sup_decode::parse_frames(&written).unwrap());
+}
+
+/// Lists the PGS streams of a transport stream and demuxes the first PGS PID.
+pub fn demux(data: &[u8]) {
+    let (_, peak) = peak_allocation(|| ts::pgs_streams(data));
+    assert!(
+        peak <= stream_allowance(data.len()),
+        "allocated {peak} bytes"
+    );
+
+    let (_, peak) = peak_allocation(|| ts::parse_frames(data, *ts::PGS_PIDS.start()));
+    assert!(
+        peak <= stream_allowance(data.len()),
+        "allocated {peak} bytes"
+    );
+}
+
+/// Decodes RLE data for an object whose dimensions are taken from the first 4 bytes.
+pub fn decode_rle(data: &[u8]) {
+    let Some((dimensions, rle)) = data.split_first_chunk::<4>() else {
+        return;
+    };
+
+    let width = u16::from_be_bytes([dimensions[0], dimensions[1]]);
+    let height = u16::from_be_bytes([dimensions[2], dimensions[3]]);
+    let pixel_count = usize::from(width) * usize::from(height);
+
+    let fragment = ObjectFragment {
+        id: 0,
+        version: 0,
+        sequence_flag: SequenceFlag::Both,
+        width,
+        height,
+        data_len: Some(rle.len() as u32 + 4),
+        data: rle,
+    };
+
+    let (pixels, peak) = peak_allocation(|| fragment.decode_pixels(RleMode::Strict).unwrap());
+    assert!(
+        peak <= MAX_PIXELS_PER_RLE_BYTE * rle.len() + 4096,
+        "allocated {peak} bytes"
+    );
+
+    if let Ok(pixels) = pixels {
+        assert_eq!(pixel_count, pixels.len());
+        assert_eq!(
+            pixels,
+            Object::encode(0, 0, width, height, &pixels).decode_pixels()
+        );
+    }
+
+    if pixel_count <= MAX_LENIENT_PIXELS {
+        let pixels = fragment.decode_pixels(RleMode::Lenient).unwrap();
+
+        if let Ok(pixels) = pixels {
+            assert_eq!(pixel_count, pixels.len());
+        }
+    }
+}
+
+/// Encodes a bitmap whose dimensions are taken from the first 2 bytes and whose pixels repeat the
+/// rest, and checks that it decodes back to the same pixels.
+pub fn rle_round_trip(data: &[u8]) {
+    let Some((&[width, height], pixels)) = data.split_first_chunk::<2>() else {
+        return;
+    };
+
+    let (width, height) = (u16::from(width), u16::from(height));
+    let pixels = pixels
+        .iter()
+        .copied()
+        .cycle()
+        .chain(std::iter::repeat(0))
+        .take(usize::from(width) * usize::from(height))
+        .collect::<Vec<_>>();
+
+    let rle = encode_rle(width, height, &pixels);
+
+    // never longer than encoding every pixel on its own
+    let naive_len = pixels
+        .iter()
+        .map(|&pixel| if pixel == 0 { 2 } else { 1 })
+        .sum::<usize>()
+        + 2 * usize::from(height);
+    assert!(rle.len() <= naive_len);
+
+    let object = Object::encode(0, 0, width, height, &pixels);
+    assert_eq!(rle, object.rle());
+    assert_eq!(pixels, object.decode_pixels());
+}
@@ -0,0 +1,69 @@
+use std::{
+    collections::{HashMap, hash_map::Entry},
+    sync::Arc,
+};
+
+use super::{object::Object, pcs, pds, wds};
+
+/// Definitions that stay valid from one Epoch Start composition to the next.
+///
+/// Normal and Acquisition Point compositions are allowed to reference objects and palettes that
+/// were defined by any earlier display set in the same epoch, so these are buffered here instead
+/// of on the display set that defined them. Palettes are keyed by ID; each PDS with that ID
+/// updates the stored palette to its version.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Epoch {
+    windows: Vec<wds::WindowDefinition>,
+    palettes: HashMap<u8, pds::PaletteDefinition>,
+    objects: HashMap<u16, Arc<Object>>,
+    shown: Vec<pcs::CompositionObject>,
+}
+
+impl Epoch {
+    pub(crate) fn clear(&mut self) {
+        self.windows.clear();
+        self.palettes.clear();
+        self.objects.clear();
+        self.shown.clear();
+    }
+
+    pub(crate) fn define_windows(&mut self, windows: Vec<wds::WindowDefinition>) {
+        self.windows = windows;
+    }
+
+    /// Stores a palette, or applies it as an update to the palette with the same ID.
+    pub(crate) fn define_palette(&mut self, palette: pds::PaletteDefinition) {
+        match self.palettes.entry(palette.id) {
+            Entry::Occupied(mut existing) => existing.get_mut().apply_update(palette),
+            Entry::Vacant(slot) => {
+                slot.insert(palette);
+            }
+        }
+    }
+
+    /// Stores an object, replacing any earlier version with the same ID.
+    pub(crate) fn define_object(&mut self, object: Object) {
+        self.objects.insert(object.id, Arc::new(object));
+    }
+
+    /// Records the composition objects currently on screen.
+    pub(crate) fn show(&mut self, composition_objects: &[pcs::CompositionObject]) {
+        self.shown = composition_objects.to_vec();
+    }
+
+    pub(crate) fn windows(&self) -> &[wds::WindowDefinition] {
+        &self.windows
+    }
+
+    pub(crate) fn shown(&self) -> &[pcs::CompositionObject] {
+        &self.shown
+    }
+
+    pub(crate) fn palette(&self, id: u8) -> Option<&pds::PaletteDefinition> {
+        self.palettes.get(&id)
+    }
+
+    pub(crate) fn object(&self, id: u16) -> Option<&Arc<Object>> {
+        self.objects.get(&id)
+    }
+}
@@ -0,0 +1,195 @@
+use std::fmt;
+
+use super::{Decoder, DisplaySet, RleMode};
+use crate::{
+    Error, SegmentType,
+    segment::{decode_segment, find_next_segment, peek_segment_type},
+};
+
+/// A problem found and skipped over by [`parse_frames_lenient`].
+#[derive(Debug)]
+pub struct Diagnostic {
+    /// Byte offset of the segment that failed to decode.
+    pub offset: usize,
+    /// Type of the failed segment, if its header was intact enough to tell.
+    pub segment_type: Option<SegmentType>,
+    /// Number of bytes skipped to reach the next plausible segment header.
+    pub skipped: usize,
+    /// What went wrong.
+    pub error: Error,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.segment_type {
+            Some(segment_type) => write!(f, "{segment_type:?} segment")?,
+            None => write!(f, "segment")?,
+        }
+
+        write!(
+            f,
+            " at byte offset {} skipped ({} bytes): {}",
+            self.offset, self.skipped, self.error,
+        )
+    }
+}
+
+/// Display sets recovered from a damaged stream, along with what had to be skipped.
+#[derive(Debug)]
+pub struct Recovered {
+    /// Display sets that decoded cleanly and show something on screen.
+    pub display_sets: Vec<DisplaySet>,
+    /// Every damaged region that was skipped, in stream order.
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// Parses a PGS stream like [`parse_frames`](super::parse_frames), but keeps going past damage.
+///
+/// When a segment fails to decode, the display set it belongs to is dropped and parsing resumes at
+/// the next plausible "PG" segment header. Every skipped region is reported as a [`Diagnostic`].
+/// Objects whose RLE data does not match their dimensions are repaired rather than dropped; see
+/// [`RleMode::Lenient`].
+pub fn parse_frames_lenient(bytes: &[u8]) -> Recovered {
+    let mut input = bytes;
+    let mut decoder = Decoder::with_rle_mode(RleMode::Lenient);
+    let mut display_sets = Vec::new();
+    let mut diagnostics = Vec::new();
+
+    for index in 0.. {
+        if input.is_empty() {
+            break;
+        }
+
+        let offset = bytes.len() - input.len();
+
+        let result = decode_segment(&mut input).and_then(|segment| decoder.push(segment));
+
+        match result {
+            Ok(display_set) => {
+                display_sets.extend(display_set.filter(|ds| !ds.is_empty()));
+            }
+            Err(error) => {
+                decoder.discard_display_set();
+
+                let resume = find_next_segment(bytes, offset).unwrap_or(bytes.len());
+                input = &bytes[resume..];
+
+                diagnostics.push(Diagnostic {
+                    offset,
+                    segment_type: peek_segment_type(&bytes[offset..]),
+                    skipped: resume - offset,
+                    error: error.at_offset(offset).in_segment(index),
+                });
+            }
+        }
+    }
+
+    Recovered {
+        display_sets,
+        diagnostics,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{ErrorKind, decode::object::Object, encode::write_display_sets, parse_segment};
+
+    #[test]
+    fn intact_stream_has_no_diagnostics() {
+        let bytes = std::fs::read("data/mummyforced.sup").unwrap();
+        let recovered = parse_frames_lenient(&bytes);
+
+        assert_eq!(22, recovered.display_sets.len());
+        assert!(recovered.diagnostics.is_empty());
+    }
+
+    #[test]
+    fn skips_display_set_with_invalid_segment() {
+        let mut bytes = std::fs::read("data/mummyforced.sup").unwrap();
+        // ODS sequence flag of the second subtitle
+        bytes[6601 + 13 + 3] = 0x11;
+
+        let recovered = parse_frames_lenient(&bytes);
+
+        assert_eq!(21, recovered.display_sets.len());
+        assert_eq!(1, recovered.diagnostics.len());
+
+        let diagnostic = &recovered.diagnostics[0];
+        assert_eq!(6601, diagnostic.offset);
+        assert_eq!(Some(SegmentType::ODS), diagnostic.segment_type);
+        assert_eq!(10_517 - 6601, diagnostic.skipped);
+        assert_eq!(Some(6601), diagnostic.error.segment_offset());
+        assert_eq!(Some(6617), diagnostic.error.offset());
+        assert_eq!(Some(11), diagnostic.error.segment_index());
+        assert!(matches!(
+            diagnostic.error.kind(),
+            ErrorKind::InvalidFlag {
+                field: "ODS sequence flag",
+                value: 0x11,
+            },
+        ));
+    }
+
+    #[test]
+    fn drops_half_assembled_object_when_resynchronising() {
+        let bytes = std::fs::read("data/small.sup").unwrap();
+        let display_sets = crate::decode::parse_display_sets(&bytes).unwrap();
+        let (shown, cleared) = (&display_sets[0], &display_sets[1]);
+
+        // noise defeats run-length encoding, so the object needs three fragments
+        let mut seed = 1u32;
+        let pixels = (0..400 * 400)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (seed >> 24) as u8 | 1
+            })
+            .collect::<Vec<_>>();
+
+        let mut fragmented = shown.clone();
+        let id = fragmented.objects[0].id;
+        fragmented.objects[0] = Arc::new(Object::encode(id, 0, 400, 400, &pixels));
+
+        let mut stream = Vec::new();
+        write_display_sets(&mut stream, [&fragmented, cleared, shown, cleared]).unwrap();
+
+        let mut input = stream.as_slice();
+        let mut ods_offsets = Vec::new();
+        while !input.is_empty() {
+            let offset = stream.len() - input.len();
+            if parse_segment(&mut input).unwrap().segment_type() == SegmentType::ODS {
+                ods_offsets.push(offset);
+            }
+        }
+
+        // break the sequence flag of the middle fragment
+        stream[ods_offsets[1] + 13 + 3] = 0x11;
+
+        let recovered = parse_frames_lenient(&stream);
+
+        // the last fragment no longer has a first fragment to continue
+        assert_eq!(2, recovered.diagnostics.len());
+        assert_eq!(ods_offsets[2], recovered.diagnostics[1].offset);
+        assert!(matches!(
+            recovered.diagnostics[1].error.kind(),
+            ErrorKind::OrphanFragment { .. },
+        ));
+        assert_eq!(vec![shown.clone()], recovered.display_sets);
+    }
+
+    #[test]
+    fn resynchronises_after_garbage() {
+        let mut bytes = std::fs::read("data/mummyforced.sup").unwrap();
+        // clobber the PCS header of the second subtitle, magic number included
+        bytes[6386..6386 + 20].fill(0xff);
+
+        let recovered = parse_frames_lenient(&bytes);
+
+        assert_eq!(21, recovered.display_sets.len());
+        assert_eq!(1, recovered.diagnostics.len());
+        assert_eq!(None, recovered.diagnostics[0].segment_type);
+        assert_eq!(6418 - 6386, recovered.diagnostics[0].skipped);
+    }
+}
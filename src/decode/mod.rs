@@ -1,13 +1,33 @@
-pub(crate) mod ods;
-pub(crate) mod pcs;
-pub(crate) mod pds;
+//! Grouping of segments into display sets.
+
+mod epoch;
+mod lenient;
+pub mod object;
+pub mod ods;
+#[cfg(feature = "parallel")]
+mod parallel;
+pub mod pcs;
+pub mod pds;
 pub(crate) mod rle;
-pub(crate) mod wds;
+pub mod wds;
 
-use chrono::NaiveTime;
-use winnow::{Bytes, error::ContextError, prelude::*};
+use std::sync::Arc;
 
-use crate::segment::{Segment, parse_segment};
+#[cfg(feature = "parallel")]
+pub use self::parallel::{decode_bitmaps_parallel, parse_frames_parallel};
+use self::{
+    epoch::Epoch,
+    object::{Object, ObjectAssembler},
+};
+pub use self::{
+    lenient::{Diagnostic, Recovered, parse_frames_lenient},
+    object::BitmapCache,
+    rle::{RgbaLut, RleMode},
+};
+use crate::{
+    Error, Timestamp,
+    segment::{Segment, SegmentData, SegmentType, decode_segment},
+};
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub(crate) enum DisplaySetState {
@@ -16,88 +36,792 @@ pub(crate) enum DisplaySetState {
     Complete,
 }
 
-#[derive(Debug, Clone)]
-pub(crate) struct DisplaySet {
-    pub(crate) pts: NaiveTime,
-    pub(crate) pcs: pcs::PresentationComposition,
-    #[expect(dead_code)]
-    pub(crate) wds: Vec<wds::WindowDefinition>,
-    pub(crate) pds: pds::PaletteDefinition,
-    pub(crate) ods: ods::ObjectDefinition,
+/// What changed on screen when a display set was presented.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisplayUpdate {
+    /// A new composition of objects.
+    Composition,
+
+    /// The objects already on screen, re-rendered with a different palette. This is how fades and
+    /// colour flashes are authored.
+    PaletteOnly,
+}
+
+/// A composition presented on screen: everything between a PCS and its END segment, resolved
+/// against the definitions of the current epoch.
+///
+/// A display set without objects clears whatever was previously on screen.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DisplaySet {
+    /// Presentation timestamp at which the composition is shown.
+    pub pts: Timestamp,
+    /// Decoding timestamp of the composition segment.
+    pub dts: Timestamp,
+    /// Whether this display set is a new composition or a palette-only update.
+    pub update: DisplayUpdate,
+    /// Composition describing where objects are placed on screen.
+    ///
+    /// Palette-only updates that omit composition objects inherit those of the composition on
+    /// screen before them.
+    pub pcs: pcs::PresentationComposition,
+    /// Windows defined for the current epoch.
+    pub wds: Vec<wds::WindowDefinition>,
+    /// Palette selected by the composition's palette ID, with every update made to it so far in
+    /// the epoch applied. Empty if a clearing composition selects an undefined palette.
+    pub pds: pds::PaletteDefinition,
+    /// Bitmaps placed by this composition, some of which may have been defined earlier in the
+    /// epoch. Each object appears once even if the composition places it more than once, and is
+    /// shared with every other display set that shows it.
+    pub objects: Vec<Arc<Object>>,
+}
+
+impl DisplaySet {
+    /// Finds the object with the given ID among those placed by this composition.
+    pub fn find_object_by_id(&self, id: u16) -> Option<&Object> {
+        self.objects
+            .iter()
+            .map(Arc::as_ref)
+            .find(|obj| obj.id == id)
+    }
+
+    /// Whether this display set clears the screen rather than showing anything.
+    pub fn is_empty(&self) -> bool {
+        self.placements().next().is_none()
+    }
+
+    /// Whether any object in this composition is flagged for forced display.
+    ///
+    /// Forced subtitles are meant to be shown even when the viewer has subtitles turned off.
+    pub fn is_forced(&self) -> bool {
+        self.placements()
+            .any(|placement| placement.composition.forced)
+    }
+
+    /// Finds the window with the given ID among those defined for this epoch.
+    pub fn find_window_by_id(&self, id: u8) -> Option<&wds::WindowDefinition> {
+        self.wds.iter().find(|window| window.id == id)
+    }
+
+    /// Iterates over every composition object along with the bitmap it places and the window it
+    /// is drawn into, in composition order.
+    pub fn placements(&self) -> impl Iterator<Item = Placement<'_>> {
+        self.pcs
+            .composition_objects
+            .iter()
+            .filter_map(|composition| {
+                Some(Placement {
+                    composition,
+                    object: self.find_object_by_id(composition.id)?,
+                    window: self.find_window_by_id(composition.window_id),
+                })
+            })
+    }
+}
+
+/// An axis-aligned rectangle in screen or bitmap pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    /// Horizontal position of the left edge.
+    pub x: u16,
+    /// Vertical position of the top edge.
+    pub y: u16,
+    /// Width in pixels.
+    pub width: u16,
+    /// Height in pixels.
+    pub height: u16,
+}
+
+impl Rect {
+    /// The largest area addressable by PGS coordinates.
+    const SCREEN: Rect = Rect {
+        x: 0,
+        y: 0,
+        width: u16::MAX,
+        height: u16::MAX,
+    };
+
+    fn right(&self) -> u32 {
+        u32::from(self.x) + u32::from(self.width)
+    }
+
+    fn bottom(&self) -> u32 {
+        u32::from(self.y) + u32::from(self.height)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    /// Returns the area covered by both rectangles, or `None` if they do not overlap.
+    pub fn intersect(&self, other: &Rect) -> Option<Rect> {
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+        let right = self.right().min(other.right());
+        let bottom = self.bottom().min(other.bottom());
+
+        let rect = Rect {
+            x,
+            y,
+            width: right.saturating_sub(u32::from(x)) as u16,
+            height: bottom.saturating_sub(u32::from(y)) as u16,
+        };
+
+        (!rect.is_empty()).then_some(rect)
+    }
+
+    /// Returns the smallest rectangle covering both rectangles.
+    pub fn union(&self, other: &Rect) -> Rect {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+
+        Rect {
+            x,
+            y,
+            width: (self.right().max(other.right()) - u32::from(x)).min(u32::from(u16::MAX)) as u16,
+            height: (self.bottom().max(other.bottom()) - u32::from(y)).min(u32::from(u16::MAX))
+                as u16,
+        }
+    }
+}
+
+/// A bitmap positioned on screen by a composition object.
+#[derive(Debug, Clone, Copy)]
+pub struct Placement<'a> {
+    /// Composition object that places the bitmap, with its position and cropping.
+    pub composition: &'a pcs::CompositionObject,
+    /// Object whose bitmap is placed.
+    pub object: &'a Object,
+    /// Window the object is drawn into, if the composition references a defined window.
+    pub window: Option<&'a wds::WindowDefinition>,
+}
+
+impl<'a> Placement<'a> {
+    /// Part of the object bitmap that is shown, in bitmap pixels.
+    ///
+    /// This is the crop rectangle when the composition object is cropped, clamped to the bitmap,
+    /// and the whole bitmap otherwise.
+    pub fn source_rect(&self) -> Rect {
+        let bitmap = Rect {
+            x: 0,
+            y: 0,
+            width: self.object.width,
+            height: self.object.height,
+        };
+
+        match self.composition.crop_rect() {
+            Some(crop) => crop.intersect(&bitmap).unwrap_or(Rect {
+                width: 0,
+                height: 0,
+                ..crop
+            }),
+            None => bitmap,
+        }
+    }
+
+    /// Screen area the (cropped) object would cover if it were not clipped to its window.
+    ///
+    /// The top-left pixel of the source rectangle is drawn at the composition object's position.
+    pub fn object_rect(&self) -> Rect {
+        let source = self.source_rect();
+
+        Rect {
+            x: self.composition.x,
+            y: self.composition.y,
+            width: source.width,
+            height: source.height,
+        }
+    }
+
+    /// Screen area that is actually drawn: the (cropped) object clipped to its window.
+    pub fn visible_rect(&self) -> Option<Rect> {
+        let mut rect = self.object_rect().intersect(&Rect::SCREEN)?;
+
+        if let Some(window) = self.window {
+            rect = rect.intersect(&window.rect())?;
+        }
+
+        Some(rect)
+    }
+
+    /// Iterates over the visible pixels as `(screen x, screen y, palette index)`, row by row.
+    ///
+    /// The object bitmap is taken from `bitmaps`, which decodes it if needed.
+    pub fn pixels<'s>(
+        &'s self,
+        bitmaps: &BitmapCache,
+    ) -> impl Iterator<Item = (u16, u16, u8)> + use<'s, 'a> {
+        let visible = self.visible_rect().unwrap_or(Rect {
+            width: 0,
+            height: 0,
+            ..Rect::SCREEN
+        });
+        let origin = self.object_rect();
+        let source = self.source_rect();
+        let stride = usize::from(self.object.width);
+        let bitmap = bitmaps.get(self.object);
+
+        (visible.y..visible.y + visible.height).flat_map(move |y| {
+            let row = usize::from(source.y + (y - origin.y)) * stride;
+            let bitmap = Arc::clone(&bitmap);
+
+            (visible.x..visible.x + visible.width).filter_map(move |x| {
+                let col = usize::from(source.x + (x - origin.x));
+                let color_id = *bitmap.get(row + col)?;
+                Some((x, y, color_id))
+            })
+        })
+    }
 }
 
 #[derive(Debug, Clone, Default)]
 struct DisplaySetBuilder {
-    pts: Option<NaiveTime>,
+    pts: Option<(Timestamp, Timestamp)>,
     pcs: Option<pcs::PresentationComposition>,
-    wds: Vec<wds::WindowDefinition>,
-    pds: Option<pds::PaletteDefinition>,
-    ods: Option<ods::ObjectDefinition>,
 }
 
 impl DisplaySetBuilder {
-    fn state(&self) -> DisplaySetState {
-        if self.pts.is_none() {
+    fn state(&self, epoch: &Epoch) -> DisplaySetState {
+        let (Some(_), Some(pcs)) = (self.pts, &self.pcs) else {
+            return DisplaySetState::Incomplete;
+        };
+
+        if pcs.composition_objects.is_empty() {
+            return DisplaySetState::EmptyFrame;
+        }
+
+        if epoch.windows().is_empty()
+            || epoch.palette(pcs.palette_id).is_none()
+            || self.placed_objects(epoch).is_empty()
+        {
             return DisplaySetState::Incomplete;
         }
 
-        if self.pcs.is_some() && !self.wds.is_empty() {
-            if self.pds.is_some() && self.ods.is_some() {
-                return DisplaySetState::Complete;
+        DisplaySetState::Complete
+    }
+
+    fn placed_objects(&self, epoch: &Epoch) -> Vec<Arc<Object>> {
+        let mut objects = Vec::<Arc<Object>>::new();
+
+        for obj in self.pcs.iter().flat_map(|pcs| &pcs.composition_objects) {
+            if objects.iter().any(|placed| placed.id == obj.id) {
+                continue;
             }
 
-            return DisplaySetState::EmptyFrame;
+            objects.extend(epoch.object(obj.id).cloned());
         }
 
-        DisplaySetState::Incomplete
+        objects
     }
 
-    fn build(self) -> DisplaySet {
+    fn build(self, epoch: &Epoch) -> DisplaySet {
+        let objects = self.placed_objects(epoch);
+        let pcs = self.pcs.unwrap();
+
+        let update = if pcs.palette_update {
+            DisplayUpdate::PaletteOnly
+        } else {
+            DisplayUpdate::Composition
+        };
+
+        let (pts, dts) = self.pts.unwrap();
+
         DisplaySet {
-            pts: self.pts.unwrap(),
-            pcs: self.pcs.unwrap(),
-            wds: self.wds,
-            pds: self.pds.unwrap(),
-            ods: self.ods.unwrap(),
+            pts,
+            dts,
+            update,
+            wds: epoch.windows().to_vec(),
+            pds: epoch
+                .palette(pcs.palette_id)
+                .cloned()
+                .unwrap_or_else(|| pds::PaletteDefinition {
+                    id: pcs.palette_id,
+                    version: 0,
+                    entries: Vec::new(),
+                }),
+            objects,
+            pcs,
         }
     }
 }
 
-pub(crate) fn parse_frames(bytes: &[u8]) -> Result<Vec<DisplaySet>, ContextError> {
-    let mut input = Bytes::new(bytes);
-    let mut display_sets = Vec::new();
-    let mut running_ds = DisplaySetBuilder::default();
+/// Incremental decoder that groups segments into display sets.
+///
+/// Objects, palettes and windows are buffered for the whole epoch so that compositions which only
+/// reference previously defined data still resolve. The buffers are cleared whenever an Epoch
+/// Start composition arrives.
+#[derive(Debug, Clone, Default)]
+pub struct Decoder {
+    epoch: Epoch,
+    objects: ObjectAssembler,
+    running_ds: DisplaySetBuilder,
+}
 
-    while !input.is_empty() {
-        let segment = parse_segment.parse_next(&mut input).map_err(|err| {
-            err.into_inner().unwrap_or_else(|_err| {
-                panic!("complete parsers should not report `ErrMode::Incomplete(_)`")
-            })
-        })?;
+impl Decoder {
+    /// Constructs a decoder with an empty epoch that rejects malformed RLE data.
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-        match segment {
-            Segment::Pcs(pts, seg) => {
-                running_ds.pts = Some(pts);
-                running_ds.pcs = Some(seg);
+    /// Constructs a decoder with an empty epoch that treats malformed RLE data according to
+    /// `rle_mode`.
+    pub fn with_rle_mode(rle_mode: RleMode) -> Self {
+        Self {
+            objects: ObjectAssembler::new(rle_mode),
+            ..Self::default()
+        }
+    }
+
+    /// Drops the display set currently being assembled, e.g. after one of its segments was lost,
+    /// along with any objects whose fragments were still arriving.
+    pub fn discard_display_set(&mut self) {
+        self.running_ds = DisplaySetBuilder::default();
+        self.objects.clear();
+    }
+
+    /// Feeds the next segment of the stream to the decoder.
+    ///
+    /// Returns a display set when `segment` ends a composition that either shows objects or
+    /// clears the screen. Fails if an object's fragments are inconsistent or its bitmap cannot be decoded.
+    pub fn push(&mut self, segment: Segment) -> Result<Option<DisplaySet>, Error> {
+        self.push_segment(segment, false)
+    }
+
+    /// Like [`Decoder::push`], but trusts that the RLE data of a complete single-fragment ODS has
+    /// already been validated if `rle_validated` is set.
+    pub(crate) fn push_segment(
+        &mut self,
+        segment: Segment,
+        rle_validated: bool,
+    ) -> Result<Option<DisplaySet>, Error> {
+        match segment.data {
+            SegmentData::Pcs(seg) => {
+                if seg.comp_state == pcs::CompositionState::EpochStart {
+                    self.epoch.clear();
+                    self.objects.clear();
+                }
+
+                self.running_ds.pts = Some((segment.pts, segment.dts));
+                self.running_ds.pcs = Some(seg);
             }
-            Segment::Wds(mut seg) => {
-                running_ds.wds.append(&mut seg);
+            SegmentData::Wds(seg) => {
+                self.epoch.define_windows(seg);
             }
-            Segment::Pds(seg) => {
-                running_ds.pds = Some(seg);
+            SegmentData::Pds(seg) => {
+                self.epoch.define_palette(seg);
             }
-            Segment::Ods(seg) => {
-                running_ds.ods = Some(seg);
+            SegmentData::Ods(seg) => {
+                let object = self
+                    .objects
+                    .push_fragment(&seg, rle_validated)
+                    .map_err(|err| {
+                        err.with_segment_type(SegmentType::ODS)
+                            .with_pts(segment.pts)
+                    })?;
+
+                if let Some(object) = object {
+                    self.epoch.define_object(object);
+                }
             }
-            Segment::End => {
-                let completed = std::mem::take(&mut running_ds);
+            SegmentData::End => {
+                let mut completed = std::mem::take(&mut self.running_ds);
+
+                let Some(pcs) = &mut completed.pcs else {
+                    return Ok(None);
+                };
+
+                if pcs.palette_update && pcs.composition_objects.is_empty() {
+                    pcs.composition_objects = self.epoch.shown().to_vec();
+                }
+
+                self.epoch.show(&pcs.composition_objects);
 
-                if completed.state() == DisplaySetState::Complete {
-                    display_sets.push(completed.build());
+                match completed.state(&self.epoch) {
+                    DisplaySetState::Incomplete => {}
+                    DisplaySetState::EmptyFrame | DisplaySetState::Complete => {
+                        return Ok(Some(completed.build(&self.epoch)));
+                    }
                 }
             }
         }
+
+        Ok(None)
     }
+}
 
+/// Parses a whole PGS stream into the display sets that show something on screen.
+///
+/// Display sets that clear the screen are skipped; use [`parse_events`](crate::parse_events) to
+/// find out when each subtitle disappears.
+pub fn parse_frames(bytes: &[u8]) -> Result<Vec<DisplaySet>, Error> {
+    let mut display_sets = parse_display_sets(bytes)?;
+    display_sets.retain(|ds| !ds.is_empty());
     Ok(display_sets)
 }
+
+/// Parses a whole PGS stream into every display set, including those that clear the screen.
+pub(crate) fn parse_display_sets(bytes: &[u8]) -> Result<Vec<DisplaySet>, Error> {
+    let mut input = bytes;
+    let mut display_sets = Vec::new();
+    let mut decoder = Decoder::new();
+
+    for index in 0.. {
+        if input.is_empty() {
+            break;
+        }
+
+        let offset = bytes.len() - input.len();
+
+        display_sets.extend(
+            decode_segment(&mut input)
+                .and_then(|segment| decoder.push(segment))
+                .map_err(|err| err.at_offset(offset).in_segment(index))?,
+        );
+    }
+
+    Ok(display_sets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use winnow::Bytes;
+
+    fn at(secs: u64) -> Timestamp {
+        Timestamp::from_millis(secs * 1_000)
+    }
+
+    fn segment(pts: Timestamp, data: SegmentData) -> Segment {
+        Segment {
+            pts,
+            dts: pts,
+            data,
+        }
+    }
+
+    fn end() -> Segment {
+        segment(at(1), SegmentData::End)
+    }
+
+    fn palette_update(palette_id: u8) -> Segment {
+        segment(
+            at(2),
+            SegmentData::Pcs(pcs::PresentationComposition {
+                comp_no: 1,
+                comp_state: pcs::CompositionState::Normal,
+                width: 1920,
+                height: 1080,
+                frame_rate: 0x10,
+                palette_id,
+                palette_update: true,
+                composition_objects: Vec::new(),
+            }),
+        )
+    }
+
+    fn pcs(comp_state: pcs::CompositionState, object_ids: &[u16]) -> Segment {
+        let composition_objects = object_ids
+            .iter()
+            .map(|&id| pcs::CompositionObject {
+                id,
+                window_id: 0,
+                cropped: false,
+                forced: false,
+                x: 0,
+                y: 0,
+                crop_x: None,
+                crop_y: None,
+                crop_width: None,
+                crop_height: None,
+            })
+            .collect();
+
+        segment(
+            at(1),
+            SegmentData::Pcs(pcs::PresentationComposition {
+                comp_no: 0,
+                comp_state,
+                width: 1920,
+                height: 1080,
+                frame_rate: 0x10,
+                palette_id: 0,
+                palette_update: false,
+                composition_objects,
+            }),
+        )
+    }
+
+    fn wds() -> Segment {
+        let data = std::fs::read("data/test/wds.dat").unwrap();
+        let wds = wds::decode_wds(&mut Bytes::new(&data[13..])).unwrap();
+        segment(at(1), SegmentData::Wds(wds))
+    }
+
+    fn pds() -> Segment {
+        let data = std::fs::read("data/test/pds.dat").unwrap();
+        let pds = pds::decode_pds(&mut Bytes::new(&data[13..])).unwrap();
+        segment(at(1), SegmentData::Pds(pds))
+    }
+
+    fn ods(id: u16) -> Segment {
+        segment(
+            at(1),
+            SegmentData::Ods(ods::ObjectDefinition {
+                id,
+                version: 0,
+                sequence_flag: ods::SequenceFlag::Both,
+                width: 1,
+                height: 1,
+                data_len: Some(7),
+                data: vec![1, 0, 0],
+            }),
+        )
+    }
+
+    #[test]
+    fn normal_composition_reuses_epoch_object() {
+        let mut decoder = Decoder::new();
+
+        for segment in [
+            pcs(pcs::CompositionState::EpochStart, &[3]),
+            wds(),
+            pds(),
+            ods(3),
+        ] {
+            assert!(decoder.push(segment).unwrap().is_none());
+        }
+        assert!(decoder.push(end()).unwrap().is_some());
+
+        assert!(
+            decoder
+                .push(pcs(pcs::CompositionState::Normal, &[]))
+                .unwrap()
+                .is_none()
+        );
+        assert!(decoder.push(end()).unwrap().unwrap().is_empty());
+
+        decoder
+            .push(pcs(pcs::CompositionState::Normal, &[3]))
+            .unwrap();
+        let ds = decoder.push(end()).unwrap().unwrap();
+
+        assert_eq!(3, ds.objects[0].id);
+        assert_eq!(2, ds.wds.len());
+    }
+
+    #[test]
+    fn keeps_every_placed_object() {
+        let mut decoder = Decoder::new();
+
+        for segment in [
+            pcs(pcs::CompositionState::EpochStart, &[1, 2, 1]),
+            wds(),
+            pds(),
+            ods(1),
+            ods(2),
+        ] {
+            decoder.push(segment).unwrap();
+        }
+        let ds = decoder.push(end()).unwrap().unwrap();
+
+        assert_eq!(2, ds.objects.len());
+        assert_eq!(
+            vec![1, 2, 1],
+            ds.placements()
+                .map(|placement| placement.object.id)
+                .collect::<Vec<_>>(),
+        );
+    }
+
+    #[test]
+    fn palette_update_rerenders_shown_objects() {
+        let mut decoder = Decoder::new();
+
+        for segment in [
+            pcs(pcs::CompositionState::EpochStart, &[3]),
+            wds(),
+            pds(),
+            ods(3),
+        ] {
+            decoder.push(segment).unwrap();
+        }
+        let shown = decoder.push(end()).unwrap().unwrap();
+        assert_eq!(DisplayUpdate::Composition, shown.update);
+
+        let SegmentData::Pds(mut faded) = pds().data else {
+            unreachable!()
+        };
+        faded.id = 1;
+        faded.entries.iter_mut().for_each(|entry| entry.alpha /= 2);
+
+        decoder.push(palette_update(1)).unwrap();
+        decoder
+            .push(segment(at(2), SegmentData::Pds(faded)))
+            .unwrap();
+        let ds = decoder.push(end()).unwrap().unwrap();
+
+        assert_eq!(DisplayUpdate::PaletteOnly, ds.update);
+        assert_eq!(at(2), ds.pts);
+        assert_eq!(1, ds.pds.id);
+        assert_eq!(
+            vec![3],
+            ds.placements().map(|p| p.object.id).collect::<Vec<_>>()
+        );
+
+        // nothing is on screen after a clearing composition, so there is nothing to re-render
+        decoder
+            .push(pcs(pcs::CompositionState::Normal, &[]))
+            .unwrap();
+        decoder.push(end()).unwrap();
+        decoder.push(palette_update(1)).unwrap();
+        assert!(decoder.push(end()).unwrap().unwrap().is_empty());
+    }
+
+    #[test]
+    fn composition_selects_palette_by_id_and_latest_version() {
+        let mut decoder = Decoder::new();
+        let SegmentData::Pds(palette) = pds().data else {
+            unreachable!()
+        };
+
+        let mut other = palette.clone();
+        other.id = 1;
+
+        let mut update = palette.clone();
+        update.version = 1;
+        update.entries.truncate(1);
+        update.entries[0].alpha = 7;
+        let updated_id = update.entries[0].id;
+
+        for segment in [
+            pcs(pcs::CompositionState::EpochStart, &[3]),
+            wds(),
+            segment(at(1), SegmentData::Pds(other)),
+            segment(at(1), SegmentData::Pds(palette.clone())),
+            segment(at(1), SegmentData::Pds(update)),
+            ods(3),
+        ] {
+            decoder.push(segment).unwrap();
+        }
+        let ds = decoder.push(end()).unwrap().unwrap();
+
+        assert_eq!(0, ds.pds.id);
+        assert_eq!(1, ds.pds.version);
+        assert_eq!(palette.entries.len(), ds.pds.entries.len());
+        assert_eq!(7, ds.pds.find_by_id(updated_id).unwrap().alpha);
+    }
+
+    #[test]
+    fn placement_is_clipped_to_window() {
+        let SegmentData::Pcs(composition) = pcs(pcs::CompositionState::EpochStart, &[0]).data
+        else {
+            unreachable!()
+        };
+        let composition = pcs::CompositionObject {
+            x: 10,
+            y: 10,
+            ..composition.composition_objects[0].clone()
+        };
+        let object = Object::from_pixels(4, 2, &(0..8).collect::<Vec<_>>());
+        let window = wds::WindowDefinition {
+            id: 0,
+            x: 11,
+            y: 10,
+            width: 2,
+            height: 5,
+        };
+
+        let placement = Placement {
+            composition: &composition,
+            object: &object,
+            window: Some(&window),
+        };
+
+        assert_eq!(
+            Some(Rect {
+                x: 11,
+                y: 10,
+                width: 2,
+                height: 2,
+            }),
+            placement.visible_rect(),
+        );
+        assert_eq!(
+            vec![(11, 10, 1), (12, 10, 2), (11, 11, 5), (12, 11, 6)],
+            placement
+                .pixels(&BitmapCache::default())
+                .collect::<Vec<_>>(),
+        );
+    }
+
+    #[test]
+    fn placement_shows_only_cropped_region() {
+        let SegmentData::Pcs(composition) = pcs(pcs::CompositionState::EpochStart, &[0]).data
+        else {
+            unreachable!()
+        };
+        let composition = pcs::CompositionObject {
+            x: 10,
+            y: 10,
+            cropped: true,
+            crop_x: Some(2),
+            crop_y: Some(1),
+            crop_width: Some(2),
+            crop_height: Some(4),
+            ..composition.composition_objects[0].clone()
+        };
+        let object = Object::from_pixels(4, 3, &(0..12).collect::<Vec<_>>());
+
+        let placement = Placement {
+            composition: &composition,
+            object: &object,
+            window: None,
+        };
+
+        assert_eq!(
+            Rect {
+                x: 10,
+                y: 10,
+                width: 2,
+                height: 2,
+            },
+            placement.object_rect(),
+        );
+        assert_eq!(
+            vec![(10, 10, 6), (11, 10, 7), (10, 11, 10), (11, 11, 11)],
+            placement
+                .pixels(&BitmapCache::default())
+                .collect::<Vec<_>>(),
+        );
+    }
+
+    #[test]
+    fn epoch_start_clears_buffered_definitions() {
+        let mut decoder = Decoder::new();
+
+        for segment in [
+            pcs(pcs::CompositionState::EpochStart, &[3]),
+            wds(),
+            pds(),
+            ods(3),
+        ] {
+            decoder.push(segment).unwrap();
+        }
+        assert!(decoder.push(end()).unwrap().is_some());
+
+        decoder
+            .push(pcs(pcs::CompositionState::EpochStart, &[3]))
+            .unwrap();
+        assert!(decoder.push(end()).unwrap().is_none());
+    }
+
+    #[test]
+    fn parses_sample_files() {
+        let bytes = std::fs::read("data/small.sup").unwrap();
+        let frames = parse_frames(&bytes).unwrap();
+        assert_eq!(1, frames.len());
+        assert!(frames[0].is_forced());
+
+        let bytes = std::fs::read("data/mummyforced.sup").unwrap();
+        assert_eq!(22, parse_frames(&bytes).unwrap().len());
+    }
+}
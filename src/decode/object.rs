@@ -0,0 +1,500 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    sync::{
+        Arc, Mutex, PoisonError,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use super::{
+    ods::ObjectDefinition,
+    rle::{RgbaLut, RleMode, decode_validated_rle, decode_validated_rle_rgba, validate_rle},
+};
+use crate::{Error, ErrorKind, encode::encode_rle};
+
+/// Source of the keys that tell objects apart in a [`BitmapCache`].
+static NEXT_BITMAP_KEY: AtomicU64 = AtomicU64::new(0);
+
+/// A complete object, reassembled from one or more ODS fragments.
+///
+/// The bitmap is kept run-length encoded; it is validated when the object is assembled, but only
+/// decoded when pixels are requested through [`Object::decode_pixels`] or a [`BitmapCache`].
+#[derive(Clone)]
+pub struct Object {
+    /// Object ID, referenced by composition objects.
+    pub id: u16,
+    /// Version of the object within its epoch.
+    pub version: u8,
+    /// Width of the bitmap in pixels.
+    pub width: u16,
+    /// Height of the bitmap in pixels.
+    pub height: u16,
+    rle: Arc<[u8]>,
+    bitmap_key: u64,
+}
+
+impl Object {
+    /// Builds an object from palette indices, one byte per pixel in row-major order, encoding
+    /// them as compactly as possible.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `pixels` is not exactly `width * height` bytes long.
+    pub fn encode(id: u16, version: u8, width: u16, height: u16, pixels: &[u8]) -> Self {
+        Self {
+            id,
+            version,
+            width,
+            height,
+            rle: encode_rle(width, height, pixels).into(),
+            bitmap_key: NEXT_BITMAP_KEY.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    /// Run-length encoded bitmap data, as reassembled from the ODS fragments.
+    pub fn rle(&self) -> &[u8] {
+        &self.rle
+    }
+
+    /// Decodes the bitmap into palette indices, one byte per pixel, in row-major order.
+    pub fn decode_pixels(&self) -> Vec<u8> {
+        let mut pixels = vec![0; self.pixel_count()];
+        self.decode_into(&mut pixels);
+        pixels
+    }
+
+    /// Decodes the bitmap into `buf`, one palette index per pixel, in row-major order.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `buf` is not exactly `width * height` bytes long.
+    pub fn decode_into(&self, buf: &mut [u8]) {
+        assert_eq!(
+            self.pixel_count(),
+            buf.len(),
+            "buffer must hold one byte per pixel"
+        );
+        decode_validated_rle(&self.rle, self.width, self.height, buf);
+    }
+
+    /// Decodes the bitmap into `buf` as RGBA, 4 bytes per pixel, in row-major order, looking up
+    /// each palette index in `lut`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `buf` is not exactly `4 * width * height` bytes long.
+    pub fn decode_rgba_into(&self, lut: &RgbaLut, buf: &mut [u8]) {
+        assert_eq!(
+            4 * self.pixel_count(),
+            buf.len(),
+            "buffer must hold 4 bytes per pixel"
+        );
+        decode_validated_rle_rgba(&self.rle, self.width, self.height, lut, buf);
+    }
+
+    fn pixel_count(&self) -> usize {
+        usize::from(self.width) * usize::from(self.height)
+    }
+}
+
+#[cfg(test)]
+impl Object {
+    /// Builds an object from palette indices, encoding every pixel on its own.
+    pub(crate) fn from_pixels(width: u16, height: u16, pixels: &[u8]) -> Self {
+        let mut rle = Vec::new();
+
+        for line in pixels.chunks(usize::from(width)) {
+            for &pixel in line {
+                match pixel {
+                    0 => rle.extend([0, 1]),
+                    _ => rle.push(pixel),
+                }
+            }
+
+            rle.extend([0, 0]);
+        }
+
+        Self {
+            id: 0,
+            version: 0,
+            width,
+            height,
+            rle: rle.into(),
+            bitmap_key: NEXT_BITMAP_KEY.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+}
+
+/// Objects are equal if they have the same id, version, dimensions and RLE data.
+impl PartialEq for Object {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+            && self.version == other.version
+            && self.width == other.width
+            && self.height == other.height
+            && self.rle == other.rle
+    }
+}
+
+impl fmt::Debug for Object {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "id={} version={} size={}x{}",
+            self.id, self.version, self.width, self.height,
+        )
+    }
+}
+
+/// An object whose first fragment has been seen but whose last fragment has not.
+#[derive(Debug, Clone)]
+struct PendingObject {
+    width: u16,
+    height: u16,
+    rle_len: usize,
+    rle: Vec<u8>,
+}
+
+/// Collects ODS fragments until an object is complete, then decodes it.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ObjectAssembler {
+    rle_mode: RleMode,
+    pending: HashMap<(u16, u8), PendingObject>,
+}
+
+/// Object data length minus the 4 bytes of dimensions, compared with the fragments received.
+fn fragment_length_error(pending: &PendingObject) -> Error {
+    Error::new(ErrorKind::InvalidLength {
+        field: "ODS object data length",
+        expected: pending.rle_len,
+        found: pending.rle.len(),
+    })
+}
+
+impl ObjectAssembler {
+    pub(crate) fn new(rle_mode: RleMode) -> Self {
+        Self {
+            rle_mode,
+            pending: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.pending.clear();
+    }
+
+    /// Adds a fragment, returning the object once its last fragment arrives.
+    #[cfg(test)]
+    pub(crate) fn push(&mut self, ods: ObjectDefinition) -> Result<Option<Object>, Error> {
+        self.push_fragment(&ods, false)
+    }
+
+    /// Adds a fragment, returning the object once its last fragment arrives. RLE validation is
+    /// skipped if `rle_validated` is set, which callers only do for objects they have checked, or
+    /// are about to check, with the same [`RleMode`].
+    pub(crate) fn push_fragment(
+        &mut self,
+        ods: &ObjectDefinition,
+        rle_validated: bool,
+    ) -> Result<Option<Object>, Error> {
+        let key = (ods.id, ods.version);
+
+        if ods.sequence_flag.has_dimensions() {
+            let data_len = ods.data_len.unwrap_or_default();
+            let rle_len = data_len.checked_sub(4).ok_or_else(|| {
+                Error::new(ErrorKind::InvalidLength {
+                    field: "ODS object data length",
+                    expected: 4,
+                    found: data_len as usize,
+                })
+            })?;
+
+            self.pending.insert(
+                key,
+                PendingObject {
+                    width: ods.width,
+                    height: ods.height,
+                    rle_len: rle_len as usize,
+                    // the data length is untrusted, so only reserve what has arrived
+                    rle: Vec::with_capacity(ods.data.len()),
+                },
+            );
+        }
+
+        let Some(pending) = self.pending.get_mut(&key) else {
+            return Err(Error::new(ErrorKind::OrphanFragment { object_id: ods.id }));
+        };
+
+        pending.rle.extend_from_slice(&ods.data);
+
+        if pending.rle.len() > pending.rle_len {
+            let pending = self.pending.remove(&key).unwrap();
+            return Err(fragment_length_error(&pending));
+        }
+
+        if !ods.sequence_flag.is_last() {
+            return Ok(None);
+        }
+
+        let pending = self.pending.remove(&key).unwrap();
+
+        if pending.rle.len() != pending.rle_len {
+            return Err(fragment_length_error(&pending));
+        }
+
+        if !rle_validated {
+            validate_rle(&pending.rle, pending.width, pending.height, self.rle_mode)?;
+        }
+
+        Ok(Some(Object {
+            id: ods.id,
+            version: ods.version,
+            width: pending.width,
+            height: pending.height,
+            rle: pending.rle.into(),
+            bitmap_key: NEXT_BITMAP_KEY.fetch_add(1, Ordering::Relaxed),
+        }))
+    }
+}
+
+/// Bounded least-recently-used cache of decoded object bitmaps.
+///
+/// Renderers only need the objects currently on screen, so keeping the few most recently drawn
+/// bitmaps decoded makes redraws cheap while memory use stays proportional to the capacity.
+#[derive(Debug)]
+pub struct BitmapCache {
+    capacity: usize,
+    /// Decoded bitmaps by object key, least recently used first.
+    entries: Mutex<VecDeque<(u64, Arc<[u8]>)>>,
+}
+
+impl BitmapCache {
+    /// Number of bitmaps kept by [`BitmapCache::default`].
+    pub const DEFAULT_CAPACITY: usize = 16;
+
+    /// Constructs a cache that keeps at most `capacity` decoded bitmaps.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Returns the decoded bitmap of `object`, decoding it if it is not cached.
+    pub fn get(&self, object: &Object) -> Arc<[u8]> {
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if let Some(index) = entries
+            .iter()
+            .position(|(key, _)| *key == object.bitmap_key)
+        {
+            let entry = entries.remove(index).unwrap();
+            let bitmap = Arc::clone(&entry.1);
+            entries.push_back(entry);
+            return bitmap;
+        }
+
+        let bitmap = Arc::<[u8]>::from(object.decode_pixels());
+        self.store(&mut entries, object, Arc::clone(&bitmap));
+
+        bitmap
+    }
+
+    /// Caches a bitmap of `object` that was decoded elsewhere, like [`BitmapCache::get`] would.
+    #[cfg(feature = "parallel")]
+    pub(crate) fn insert(&self, object: &Object, bitmap: Arc<[u8]>) {
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        self.store(&mut entries, object, bitmap);
+    }
+
+    /// Adds a bitmap as the most recently used one, evicting the least recently used if full.
+    fn store(&self, entries: &mut VecDeque<(u64, Arc<[u8]>)>, object: &Object, bitmap: Arc<[u8]>) {
+        if self.capacity > 0 {
+            if entries.len() == self.capacity {
+                entries.pop_front();
+            }
+
+            entries.push_back((object.bitmap_key, bitmap));
+        }
+    }
+
+    /// Number of bitmaps currently cached.
+    pub fn len(&self) -> usize {
+        self.entries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len()
+    }
+
+    /// Returns `true` if no bitmaps are cached.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for BitmapCache {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_CAPACITY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::ods::SequenceFlag;
+
+    fn fragment(sequence_flag: SequenceFlag, data: &[u8]) -> ObjectDefinition {
+        let has_dimensions = sequence_flag.has_dimensions();
+
+        ObjectDefinition {
+            id: 7,
+            version: 1,
+            sequence_flag,
+            width: if has_dimensions { 4 } else { 0 },
+            height: if has_dimensions { 2 } else { 0 },
+            data_len: has_dimensions.then_some(4 + 10),
+            data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn reassembles_fragments_before_decoding() {
+        let mut assembler = ObjectAssembler::default();
+
+        // the color-run code `00 84 05` is split across fragment boundaries
+        assert!(
+            assembler
+                .push(fragment(SequenceFlag::First, &[0, 0x84]))
+                .unwrap()
+                .is_none()
+        );
+        assert!(
+            assembler
+                .push(fragment(SequenceFlag::Middle, &[5, 0, 0]))
+                .unwrap()
+                .is_none()
+        );
+        let obj = assembler
+            .push(fragment(SequenceFlag::Last, &[0, 0x84, 6, 0, 0]))
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(7, obj.id);
+        assert_eq!((4, 2), (obj.width, obj.height));
+        assert_eq!(vec![5, 5, 5, 5, 6, 6, 6, 6], obj.decode_pixels());
+    }
+
+    #[test]
+    fn rejects_fragment_length_mismatch() {
+        let mut assembler = ObjectAssembler::default();
+
+        assembler
+            .push(fragment(SequenceFlag::First, &[0, 0x84, 5]))
+            .unwrap();
+        assembler
+            .push(fragment(SequenceFlag::Last, &[0, 0]))
+            .unwrap_err();
+
+        assembler
+            .push(fragment(
+                SequenceFlag::Both,
+                &[0, 0x84, 5, 0, 0, 0, 0x84, 6, 0, 0, 0],
+            ))
+            .unwrap_err();
+    }
+
+    #[test]
+    fn rejects_orphan_continuation() {
+        let mut assembler = ObjectAssembler::default();
+
+        let err = assembler
+            .push(fragment(SequenceFlag::Middle, &[1]))
+            .unwrap_err();
+        assert!(matches!(
+            err.kind(),
+            ErrorKind::OrphanFragment { object_id: 7 }
+        ));
+    }
+
+    #[test]
+    fn rejects_rle_not_matching_dimensions() {
+        let mut assembler = ObjectAssembler::default();
+
+        // a 5 pixel line in a 4x2 object
+        let rle = [0, 0x85, 5, 0, 0, 0, 0x84, 6, 0, 0];
+        let err = assembler
+            .push(fragment(SequenceFlag::Both, &rle))
+            .unwrap_err();
+        assert!(matches!(
+            err.kind(),
+            ErrorKind::RleLineWidth {
+                line: 0,
+                expected: 4,
+                found: 5,
+            }
+        ));
+
+        let mut assembler = ObjectAssembler::new(RleMode::Lenient);
+        let obj = assembler
+            .push(fragment(SequenceFlag::Both, &rle))
+            .unwrap()
+            .unwrap();
+        assert_eq!(vec![5, 5, 5, 5, 6, 6, 6, 6], obj.decode_pixels());
+    }
+
+    #[test]
+    fn bitmap_cache_evicts_least_recently_used() {
+        let mut assembler = ObjectAssembler::default();
+        let mut object = |color| {
+            assembler
+                .push(fragment(
+                    SequenceFlag::Both,
+                    &[0, 0x84, color, 0, 0, 0, 0x84, color, 0, 0],
+                ))
+                .unwrap()
+                .unwrap()
+        };
+        let (a, b, c) = (object(1), object(2), object(3));
+
+        let cache = BitmapCache::new(2);
+        let first = cache.get(&a);
+        assert_eq!(vec![1; 8], *first);
+        assert!(Arc::ptr_eq(&first, &cache.get(&a)));
+
+        cache.get(&b);
+        // `a` was used more recently than `b`, so `b` makes way for `c`
+        cache.get(&a);
+        cache.get(&c);
+
+        assert_eq!(2, cache.len());
+        assert!(Arc::ptr_eq(&first, &cache.get(&a)));
+        assert_eq!(vec![2; 8], *cache.get(&b));
+    }
+
+    #[test]
+    fn decodes_into_caller_buffers() {
+        let object = Object::from_pixels(2, 2, &[1, 0, 0, 2]);
+
+        let mut pixels = [0xff; 4];
+        object.decode_into(&mut pixels);
+        assert_eq!([1, 0, 0, 2], pixels);
+
+        let mut lut = [[0; 4]; 256];
+        lut[1] = [10, 20, 30, 255];
+        lut[2] = [40, 50, 60, 128];
+
+        let mut rgba = [0xff; 16];
+        object.decode_rgba_into(&lut, &mut rgba);
+        assert_eq!(
+            [10, 20, 30, 255, 0, 0, 0, 0, 0, 0, 0, 0, 40, 50, 60, 128],
+            rgba,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "one byte per pixel")]
+    fn decode_into_rejects_wrong_buffer_size() {
+        Object::from_pixels(2, 2, &[1, 0, 0, 2]).decode_into(&mut [0; 3]);
+    }
+}
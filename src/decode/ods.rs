@@ -3,22 +3,58 @@ use std::fmt;
 use winnow::{
     Bytes, ModalResult,
     binary::{be_u8, be_u16, be_u24},
-    error::{ContextError, ErrMode, StrContext, StrContextValue},
+    error::{StrContext, StrContextValue},
     prelude::*,
-    token::take,
+    token::rest,
 };
 
-use crate::decode::rle::decode_rle_stream;
-
-#[derive(Clone, Hash)]
-pub(crate) struct ObjectDefinition {
-    pub(crate) id: u16,
-    pub(crate) version: u8,
-    pub(crate) sequence_flag: SequenceFlag,
-    pub(crate) width: u16,
-    pub(crate) height: u16,
-    data_len: u32,
-    pub(crate) data: Vec<u8>,
+use super::rle::{RleMode, decode_rle_stream};
+use crate::Error;
+
+// The Object Definition Segment carries the run-length encoded bitmap of an object. Objects that
+// do not fit in a single segment are split across several, flagged First, (Middle, ...) Last.
+// Name                    Bytes    Description
+// Object ID               2        ID of this object
+// Object Version Number   1        Version of this object
+// Last in Sequence Flag   1        0x40: Last, 0x80: First, 0xC0: First and last (complete object)
+// Object Data Length      3        Length of the RLE data buffer, including width and height.
+//                                  Only present in the first fragment.
+// Width                   2        Width of the image. Only present in the first fragment.
+// Height                  2        Height of the image. Only present in the first fragment.
+// Object Data             variable This object's fragment of the RLE bitmap.
+
+/// Object Definition Segment
+///
+/// This is a single fragment of an object; see [`Object`](super::object::Object) for the
+/// reassembled and decoded bitmap.
+#[derive(Clone, PartialEq, Hash)]
+pub struct ObjectDefinition {
+    pub id: u16,
+    pub version: u8,
+    pub sequence_flag: SequenceFlag,
+    /// Object width. Zero for continuation fragments.
+    pub width: u16,
+    /// Object height. Zero for continuation fragments.
+    pub height: u16,
+    /// Length of the full RLE bitmap plus 4 bytes for the dimensions. Only present in the first
+    /// fragment.
+    pub data_len: Option<u32>,
+    /// This fragment's run-length encoded bitmap data.
+    pub data: Vec<u8>,
+}
+
+impl From<ObjectFragment<'_>> for ObjectDefinition {
+    fn from(fragment: ObjectFragment<'_>) -> Self {
+        Self {
+            id: fragment.id,
+            version: fragment.version,
+            sequence_flag: fragment.sequence_flag,
+            width: fragment.width,
+            height: fragment.height,
+            data_len: fragment.data_len,
+            data: fragment.data.to_vec(),
+        }
+    }
 }
 
 impl fmt::Debug for ObjectDefinition {
@@ -27,8 +63,41 @@ impl fmt::Debug for ObjectDefinition {
     }
 }
 
+/// Object Definition Segment borrowing its RLE data from the input.
+///
+/// This is the zero-copy counterpart of [`ObjectDefinition`], produced by
+/// [`raw::Segment::object_fragment`](crate::raw::Segment::object_fragment).
+#[derive(Debug, Clone, Copy)]
+pub struct ObjectFragment<'a> {
+    pub id: u16,
+    pub version: u8,
+    pub sequence_flag: SequenceFlag,
+    /// Object width. Zero for continuation fragments.
+    pub width: u16,
+    /// Object height. Zero for continuation fragments.
+    pub height: u16,
+    /// Length of the full RLE bitmap plus 4 bytes for the dimensions. Only present in the first
+    /// fragment.
+    pub data_len: Option<u32>,
+    /// This fragment's run-length encoded bitmap data.
+    pub data: &'a [u8],
+}
+
+impl ObjectFragment<'_> {
+    /// Decodes the bitmap into one palette index per pixel, if this fragment holds a complete
+    /// object.
+    ///
+    /// Objects split across several segments have to be reassembled first, which a
+    /// [`Decoder`](crate::Decoder) does.
+    pub fn decode_pixels(&self, rle_mode: RleMode) -> Option<Result<Vec<u8>, Error>> {
+        (self.sequence_flag == SequenceFlag::Both)
+            .then(|| decode_rle_stream(self.data, self.width, self.height, rle_mode))
+    }
+}
+
+/// Position of an ODS fragment within an object split across several segments.
 #[derive(Debug, Clone, Copy, PartialEq, Hash)]
-pub(crate) enum SequenceFlag {
+pub enum SequenceFlag {
     Middle,
     First,
     Last,
@@ -36,9 +105,13 @@ pub(crate) enum SequenceFlag {
 }
 
 impl SequenceFlag {
-    fn has_dimensions(self) -> bool {
+    pub(crate) fn has_dimensions(self) -> bool {
         matches!(self, Self::First | Self::Both)
     }
+
+    pub(crate) fn is_last(self) -> bool {
+        matches!(self, Self::Last | Self::Both)
+    }
 }
 
 fn parse_sequence_flag(input: &mut &Bytes) -> ModalResult<SequenceFlag> {
@@ -68,51 +141,30 @@ fn parse_dimensions(input: &mut &Bytes) -> ModalResult<(u16, u16)> {
         .parse_next(input)
 }
 
-fn parse_object_data(
-    input: &mut &Bytes,
-    sequence_flag: SequenceFlag,
-    data_len: u32,
-) -> ModalResult<(u16, u16, Vec<u8>)> {
-    let (width, height, rle_len) = if sequence_flag.has_dimensions() {
-        let (width, height) = parse_dimensions.parse_next(input)?;
-        let rle_len = data_len.checked_sub(4).ok_or_else(|| {
-            let mut err = ContextError::new();
-            err.push(StrContext::Label("ODS object data length"));
-            ErrMode::Backtrack(err)
-        })?;
-        (width, height, rle_len)
-    } else {
-        (0, 0, data_len)
-    };
-
-    let mut rle_input = take(rle_len as usize)
-        .context(StrContext::Label("ODS object data"))
-        .map(Bytes::new)
-        .parse_next(input)?;
-    let expected_pixels = usize::from(width) * usize::from(height);
-    let data = decode_rle_stream(&mut rle_input, expected_pixels).map_err(|err| {
-        err.map(|mut inner| {
-            inner.push(StrContext::Label("ODS RLE data"));
-            inner
-        })
-    })?;
-
-    Ok((width, height, data))
-}
-
-pub(crate) fn decode_ods(input: &mut &Bytes) -> ModalResult<ObjectDefinition> {
-    let (id, version, sequence_flag, data_len) = (
+pub(crate) fn decode_ods<'a>(input: &mut &'a Bytes) -> ModalResult<ObjectFragment<'a>> {
+    let (id, version, sequence_flag) = (
         be_u16.context(StrContext::Label("ODS object id")),
         be_u8.context(StrContext::Label("ODS version")),
         parse_sequence_flag,
-        be_u24.context(StrContext::Label("ODS object data length")),
     )
         .context(StrContext::Label("ODS header"))
         .parse_next(input)?;
 
-    let (width, height, data) = parse_object_data(input, sequence_flag, data_len)?;
+    let (data_len, width, height) = if sequence_flag.has_dimensions() {
+        let data_len = be_u24
+            .context(StrContext::Label("ODS object data length"))
+            .parse_next(input)?;
+        let (width, height) = parse_dimensions.parse_next(input)?;
+        (Some(data_len), width, height)
+    } else {
+        (None, 0, 0)
+    };
+
+    let data = rest
+        .context(StrContext::Label("ODS object data"))
+        .parse_next(input)?;
 
-    Ok(ObjectDefinition {
+    Ok(ObjectFragment {
         id,
         version,
         sequence_flag,
@@ -137,8 +189,8 @@ mod tests {
         assert_eq!(SequenceFlag::Both, ods.sequence_flag);
         assert_eq!(741, ods.width);
         assert_eq!(60, ods.height);
-        assert_eq!(0x00_4506, ods.data_len);
-        assert_eq!(ods.width as usize * ods.height as usize, ods.data.len());
+        assert_eq!(Some(0x00_4506), ods.data_len);
+        assert_eq!(0x00_4506 - 4, ods.data.len());
     }
 
     #[test]
@@ -152,7 +204,6 @@ mod tests {
             0x12, 0x34, // object id
             0x02, // version
             0x00, // middle fragment
-            0x00, 0x00, 0x01, // object data length
             0x2a, // one pixel
         ];
 
@@ -163,6 +214,7 @@ mod tests {
         assert_eq!(SequenceFlag::Middle, ods.sequence_flag);
         assert_eq!(0, ods.width);
         assert_eq!(0, ods.height);
+        assert_eq!(None, ods.data_len);
         assert_eq!(vec![0x2a], ods.data);
     }
 }
@@ -0,0 +1,287 @@
+//! Multi-threaded parsing and bitmap decoding, enabled by the `parallel` cargo feature.
+
+use std::{collections::HashSet, sync::Arc};
+
+use rayon::prelude::*;
+
+use super::{
+    BitmapCache, Decoder, DisplaySet,
+    object::{Object, ObjectAssembler},
+    ods::SequenceFlag,
+    pcs::CompositionState,
+    rle::{RleMode, validate_rle},
+};
+use crate::{Error, Segment, SegmentData, SegmentType, Timestamp, raw};
+
+/// Parses a whole PGS stream like [`parse_frames`](super::parse_frames), parsing segment
+/// payloads and validating RLE data on every available thread.
+///
+/// Segment headers are scanned first to locate every payload. Payloads are then parsed, and the
+/// RLE data of single-fragment objects validated, independently of each other. Objects split
+/// across several fragments are reassembled in a quick sequential pass and then validated in
+/// parallel too; only the grouping into display sets runs sequentially. The result, including
+/// which error is reported for a damaged stream, is the same as that of `parse_frames`.
+///
+/// Objects stay run-length encoded, as with `parse_frames`; pass the result to
+/// [`decode_bitmaps_parallel`] to decode every bitmap on all threads as well.
+pub fn parse_frames_parallel(bytes: &[u8]) -> Result<Vec<DisplaySet>, Error> {
+    let mut headers = raw::segments(bytes);
+    let mut segments = Vec::new();
+    let mut header_error = None;
+
+    loop {
+        let offset = headers.offset();
+
+        match headers.next() {
+            Some(Ok(segment)) => segments.push((offset, segment)),
+            Some(Err(err)) => {
+                header_error = Some(err);
+                break;
+            }
+            None => break,
+        }
+    }
+
+    let decoded = segments
+        .par_iter()
+        .map(|(_, segment)| decode(segment))
+        .collect::<Vec<_>>();
+
+    let mut fragmented = fragmented_objects(&decoded)
+        .into_par_iter()
+        .map(|(index, pts, object)| {
+            let validated =
+                validate_rle(object.rle(), object.width, object.height, RleMode::Strict)
+                    .map_err(|err| err.with_segment_type(SegmentType::ODS).with_pts(pts));
+            (index, validated)
+        })
+        .collect::<Vec<_>>()
+        .into_iter()
+        .peekable();
+
+    let mut decoder = Decoder::new();
+    let mut display_sets = Vec::new();
+
+    for (index, ((offset, _), result)) in segments.iter().zip(decoded).enumerate() {
+        display_sets.extend(
+            result
+                .and_then(|(segment, rle_validated)| {
+                    // the last fragment of a reassembled object, which has been validated above
+                    let rle_validated = match fragmented.next_if(|(last, _)| *last == index) {
+                        Some((_, validated)) => validated.map(|()| true)?,
+                        None => rle_validated,
+                    };
+
+                    decoder.push_segment(segment, rle_validated)
+                })
+                .map_err(|err| err.at_offset(*offset).in_segment(index))?,
+        );
+    }
+
+    if let Some(err) = header_error {
+        return Err(err);
+    }
+
+    display_sets.retain(|ds| !ds.is_empty());
+    Ok(display_sets)
+}
+
+/// Decodes the bitmap of every object placed by `display_sets` on every available thread.
+///
+/// Returns a [`BitmapCache`] large enough to hold all of them, so that drawing any of the display
+/// sets through it never decodes again. Objects shared by several display sets are decoded once.
+pub fn decode_bitmaps_parallel(display_sets: &[DisplaySet]) -> BitmapCache {
+    let mut seen = HashSet::new();
+    let objects = display_sets
+        .iter()
+        .flat_map(|ds| &ds.objects)
+        .filter(|object| seen.insert(Arc::as_ptr(object)))
+        .collect::<Vec<_>>();
+
+    let bitmaps = objects
+        .par_iter()
+        .map(|object| Arc::<[u8]>::from(object.decode_pixels()))
+        .collect::<Vec<_>>();
+
+    let cache = BitmapCache::new(objects.len());
+
+    for (object, bitmap) in objects.into_iter().zip(bitmaps) {
+        cache.insert(object, bitmap);
+    }
+
+    cache
+}
+
+/// Reassembles the objects whose RLE data was not validated with their payload, returning each
+/// with the index and PTS of the segment that completes it.
+///
+/// The fragments go through an assembler of their own, cleared where the decoder's would be, so
+/// it completes the same objects. It stops at the first error, which the decoder reports.
+fn fragmented_objects(
+    decoded: &[Result<(Segment, bool), Error>],
+) -> Vec<(usize, Timestamp, Object)> {
+    let mut assembler = ObjectAssembler::default();
+    let mut objects = Vec::new();
+
+    for (index, result) in decoded.iter().enumerate() {
+        let Ok((segment, rle_validated)) = result else {
+            break;
+        };
+
+        match &segment.data {
+            SegmentData::Pcs(pcs) if pcs.comp_state == CompositionState::EpochStart => {
+                assembler.clear();
+            }
+            SegmentData::Ods(ods) => match assembler.push_fragment(ods, true) {
+                Ok(Some(object)) if !rle_validated => objects.push((index, segment.pts, object)),
+                Ok(_) => {}
+                Err(_) => break,
+            },
+            _ => {}
+        }
+    }
+
+    objects
+}
+
+/// Decodes the payload of `segment` and, if it holds a complete object, validates its RLE data.
+///
+/// Returns whether the RLE data was validated.
+fn decode(segment: &raw::Segment<'_>) -> Result<(Segment, bool), Error> {
+    let segment = segment.decode()?;
+
+    let SegmentData::Ods(ods) = &segment.data else {
+        return Ok((segment, false));
+    };
+
+    // anything else is left to the decoder, which reports fragment errors before RLE errors
+    let complete = ods.sequence_flag == SequenceFlag::Both
+        && ods
+            .data_len
+            .is_some_and(|data_len| data_len as usize == ods.data.len() + 4);
+
+    if !complete {
+        return Ok((segment, false));
+    }
+
+    validate_rle(&ods.data, ods.width, ods.height, RleMode::Strict).map_err(|err| {
+        err.with_segment_type(SegmentType::ODS)
+            .with_pts(segment.pts)
+    })?;
+
+    Ok((segment, true))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ErrorKind, encode::write_display_sets, parse_frames, parse_segment};
+
+    /// The sample stream with its first object replaced by noise, which needs three ODS
+    /// fragments, and the offsets of its ODS segments.
+    fn fragmented_stream() -> (Vec<u8>, Vec<usize>) {
+        let bytes = std::fs::read("data/small.sup").unwrap();
+        let display_sets = crate::decode::parse_display_sets(&bytes).unwrap();
+        let (shown, cleared) = (&display_sets[0], &display_sets[1]);
+
+        let mut seed = 1u32;
+        let pixels = (0..400 * 400)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (seed >> 24) as u8 | 1
+            })
+            .collect::<Vec<_>>();
+
+        let mut fragmented = shown.clone();
+        let id = fragmented.objects[0].id;
+        fragmented.objects[0] = Arc::new(Object::encode(id, 0, 400, 400, &pixels));
+
+        let mut stream = Vec::new();
+        write_display_sets(&mut stream, [&fragmented, cleared, shown, cleared]).unwrap();
+
+        let mut input = stream.as_slice();
+        let mut ods_offsets = Vec::new();
+        while !input.is_empty() {
+            let offset = stream.len() - input.len();
+            if parse_segment(&mut input).unwrap().segment_type() == SegmentType::ODS {
+                ods_offsets.push(offset);
+            }
+        }
+
+        (stream, ods_offsets)
+    }
+
+    #[test]
+    fn matches_sequential_parser() {
+        for file in ["data/small.sup", "data/mummyforced.sup"] {
+            let bytes = std::fs::read(file).unwrap();
+
+            let expected = parse_frames(&bytes).unwrap();
+            let parallel = parse_frames_parallel(&bytes).unwrap();
+
+            assert_eq!(expected.len(), parallel.len());
+
+            for (expected, parallel) in expected.iter().zip(&parallel) {
+                assert_eq!(expected.pts, parallel.pts);
+                assert_eq!(expected.objects.len(), parallel.objects.len());
+                assert_eq!(expected.objects[0].rle(), parallel.objects[0].rle());
+            }
+        }
+    }
+
+    #[test]
+    fn validates_fragmented_objects_like_sequential_parser() {
+        let (mut stream, ods_offsets) = fragmented_stream();
+        assert_eq!(4, ods_offsets.len());
+        assert_eq!(
+            parse_frames(&stream).unwrap(),
+            parse_frames_parallel(&stream).unwrap()
+        );
+
+        // end a line early in the middle fragment, which only shows once the object is complete
+        let data = ods_offsets[1] + 13 + 4;
+        stream[data + 100..data + 102].fill(0);
+
+        let expected = parse_frames(&stream).unwrap_err();
+        let parallel = parse_frames_parallel(&stream).unwrap_err();
+
+        assert!(matches!(parallel.kind(), ErrorKind::RleLineWidth { .. }));
+        assert_eq!(Some(ods_offsets[2]), parallel.segment_offset());
+        assert_eq!(expected.to_string(), parallel.to_string());
+        assert_eq!(expected.segment_index(), parallel.segment_index());
+    }
+
+    #[test]
+    fn decodes_every_bitmap_once() {
+        let (stream, _) = fragmented_stream();
+        let frames = parse_frames_parallel(&stream).unwrap();
+
+        let bitmaps = decode_bitmaps_parallel(&frames);
+
+        assert_eq!(2, bitmaps.len());
+        for object in frames.iter().flat_map(|frame| &frame.objects) {
+            assert_eq!(object.decode_pixels(), *bitmaps.get(object));
+        }
+        assert_eq!(2, bitmaps.len());
+    }
+
+    #[test]
+    fn reports_same_error_as_sequential_parser() {
+        let mut bytes = std::fs::read("data/mummyforced.sup").unwrap();
+        // first RLE byte of the second subtitle's object, turning a pixel into a long run
+        bytes[6601 + 13 + 11] = 0;
+        bytes[6601 + 13 + 12] = 0x7f;
+        // and truncate the stream in a later segment header
+        bytes.truncate(10_517 + 5);
+
+        let expected = parse_frames(&bytes).unwrap_err();
+        let parallel = parse_frames_parallel(&bytes).unwrap_err();
+
+        assert!(matches!(
+            parallel.kind(),
+            ErrorKind::RleLineWidth { line: 0, .. }
+        ));
+        assert_eq!(expected.to_string(), parallel.to_string());
+        assert_eq!(expected.segment_index(), parallel.segment_index());
+    }
+}
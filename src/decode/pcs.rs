@@ -7,6 +7,8 @@ use winnow::{
     prelude::*,
 };
 
+use super::Rect;
+
 // The Presentation Composition Segment is used for composing a sub picture. It is made of the following fields:
 // Name                             Bytes    Description
 // Width                            2        Video width in pixels (ex. 0x780 = 1920)
@@ -27,19 +29,20 @@ use winnow::{
 // Name                                   Bytes    Description
 // Object ID                              2        ID of the ODS segment that defines the image to be shown
 // Window ID                              1        Id of the WDS segment to which the image is allocated in the PCS. Up to two images may be assigned to one window
-// Object Cropped Flag                    1        0x40: Force display of the cropped image object
-//                                                 0x00: Off
+// Object Flags                           1        Bitfield:
+//                                                 0x80: Object is cropped
+//                                                 0x40: Forced display (shown even when subtitles are off)
 // Object Horizontal Position             2        X offset from the top left pixel of the image on the screen
 // Object Vertical Position               2        Y offset from the top left pixel of the image on the screen
-// Object Cropping Horizontal Position    2        X offset from the top left pixel of the cropped object in the screen. Only used when the Object Cropped Flag is set to 0x40.
-// Object Cropping Vertical Position      2        Y offset from the top left pixel of the cropped object in the screen. Only used when the Object Cropped Flag is set to 0x40.
-// Object Cropping Width                  2        Width of the cropped object in the screen. Only used when the Object Cropped Flag is set to 0x40.
-// Object Cropping Height Position        2        Height of the cropped object in the screen. Only used when the Object Cropped Flag is set to 0x40.
+// Object Cropping Horizontal Position    2        X offset from the top left pixel of the cropped object in the screen. Only present when the cropped flag (0x80) is set.
+// Object Cropping Vertical Position      2        Y offset from the top left pixel of the cropped object in the screen. Only present when the cropped flag (0x80) is set.
+// Object Cropping Width                  2        Width of the cropped object in the screen. Only present when the cropped flag (0x80) is set.
+// Object Cropping Height Position        2        Height of the cropped object in the screen. Only present when the cropped flag (0x80) is set.
 
-// When the Object Cropped Flag is set to true (or actually 0x40), then the sub picture is cropped to show only a portion of it. This is used for example when you don’t want to show the whole subtitle at first, but just a few words first, and then the rest.
+// When the cropped flag is set, then the sub picture is cropped to show only a portion of it. This is used for example when you don’t want to show the whole subtitle at first, but just a few words first, and then the rest.
 
 #[derive(Debug, Clone, Copy, PartialEq)]
-pub(crate) enum CompositionState {
+pub enum CompositionState {
     /// This defines a new display. The Epoch Start contains all functional segments needed to
     /// display a new composition on the screen.
     EpochStart,
@@ -58,19 +61,22 @@ pub(crate) enum CompositionState {
 }
 
 /// Presentation Composition Segment
-#[derive(Clone)]
-pub(crate) struct PresentationComposition {
-    pub(crate) comp_no: u16,
-    pub(crate) comp_state: CompositionState,
-    pub(crate) width: u16,
-    pub(crate) height: u16,
-    pub(crate) palette_id: u8,
-    pub(crate) palette_update: bool,
-    pub(crate) composition_objects: Vec<CompositionObject>,
+#[derive(Clone, PartialEq)]
+pub struct PresentationComposition {
+    pub comp_no: u16,
+    pub comp_state: CompositionState,
+    pub width: u16,
+    pub height: u16,
+    /// Frame rate code of the video. Always 0x10 in practice, but kept so the segment can be
+    /// written back unchanged.
+    pub frame_rate: u8,
+    pub palette_id: u8,
+    pub palette_update: bool,
+    pub composition_objects: Vec<CompositionObject>,
 }
 
 impl PresentationComposition {
-    pub(crate) fn find_object_by_id(&self, id: u16) -> Option<&CompositionObject> {
+    pub fn find_object_by_id(&self, id: u16) -> Option<&CompositionObject> {
         self.composition_objects.iter().find(|obj| obj.id == id)
     }
 }
@@ -101,17 +107,38 @@ impl fmt::Debug for PresentationComposition {
     }
 }
 
+/// Placement of a single object within a [`PresentationComposition`].
 #[derive(Clone, PartialEq)]
-pub(crate) struct CompositionObject {
-    pub(crate) id: u16,
-    pub(crate) window_id: u8,
-    pub(crate) cropped: bool,
-    pub(crate) x: u16,
-    pub(crate) y: u16,
-    pub(crate) crop_x: Option<u16>,
-    pub(crate) crop_y: Option<u16>,
-    pub(crate) crop_width: Option<u16>,
-    pub(crate) crop_height: Option<u16>,
+pub struct CompositionObject {
+    pub id: u16,
+    pub window_id: u8,
+    pub cropped: bool,
+    /// Forced objects are shown even when the viewer has subtitles turned off, typically to
+    /// translate signs or foreign-language dialogue.
+    pub forced: bool,
+    pub x: u16,
+    pub y: u16,
+    pub crop_x: Option<u16>,
+    pub crop_y: Option<u16>,
+    pub crop_width: Option<u16>,
+    pub crop_height: Option<u16>,
+}
+
+impl CompositionObject {
+    /// Part of the object bitmap to show, if the object is cropped and the crop rectangle is
+    /// present.
+    pub fn crop_rect(&self) -> Option<Rect> {
+        if !self.cropped {
+            return None;
+        }
+
+        Some(Rect {
+            x: self.crop_x?,
+            y: self.crop_y?,
+            width: self.crop_width?,
+            height: self.crop_height?,
+        })
+    }
 }
 
 impl fmt::Debug for CompositionObject {
@@ -137,6 +164,10 @@ impl fmt::Debug for CompositionObject {
             write!(f, ", cropped={}", self.cropped)?;
         }
 
+        if self.forced {
+            write!(f, ", forced")?;
+        }
+
         Ok(())
     }
 }
@@ -174,18 +205,25 @@ fn parse_palette_update(input: &mut &Bytes) -> ModalResult<bool> {
         .parse_next(input)
 }
 
-fn parse_object_cropped_flag(input: &mut &Bytes) -> ModalResult<bool> {
+pub(crate) const OBJECT_FLAG_CROPPED: u8 = 0x80;
+pub(crate) const OBJECT_FLAG_FORCED: u8 = 0x40;
+
+/// Parses the composition object flag byte into `(cropped, forced)`.
+fn parse_object_flags(input: &mut &Bytes) -> ModalResult<(bool, bool)> {
     be_u8
         .verify_map(|byte| {
-            Some(match byte {
-                0x40 => true,
-                0x00 => false,
-                _ => return None,
-            })
+            if byte & !(OBJECT_FLAG_CROPPED | OBJECT_FLAG_FORCED) != 0 {
+                return None;
+            }
+
+            Some((
+                byte & OBJECT_FLAG_CROPPED != 0,
+                byte & OBJECT_FLAG_FORCED != 0,
+            ))
         })
-        .context(StrContext::Label("PCS composition object cropped flag"))
+        .context(StrContext::Label("PCS composition object flags"))
         .context(StrContext::Expected(StrContextValue::Description(
-            "0x00 or 0x40",
+            "only the cropped (0x80) and forced (0x40) bits",
         )))
         .parse_next(input)
 }
@@ -202,10 +240,10 @@ fn parse_crop_rect(input: &mut &Bytes) -> ModalResult<(u16, u16, u16, u16)> {
 }
 
 fn parse_composition_object(input: &mut &Bytes) -> ModalResult<CompositionObject> {
-    let (id, window_id, cropped, x, y) = (
+    let (id, window_id, (cropped, forced), x, y) = (
         be_u16.context(StrContext::Label("PCS composition object id")),
         be_u8.context(StrContext::Label("PCS composition object window id")),
-        parse_object_cropped_flag,
+        parse_object_flags,
         be_u16.context(StrContext::Label("PCS composition object x")),
         be_u16.context(StrContext::Label("PCS composition object y")),
     )
@@ -231,6 +269,7 @@ fn parse_composition_object(input: &mut &Bytes) -> ModalResult<CompositionObject
         id,
         window_id,
         cropped,
+        forced,
         x,
         y,
         crop_x,
@@ -244,7 +283,7 @@ pub(crate) fn decode_pcs(input: &mut &Bytes) -> ModalResult<PresentationComposit
     (
         be_u16.context(StrContext::Label("PCS width")),
         be_u16.context(StrContext::Label("PCS height")),
-        be_u8.void().context(StrContext::Label("PCS frame rate")),
+        be_u8.context(StrContext::Label("PCS frame rate")),
         be_u16.context(StrContext::Label("PCS composition number")),
         parse_comp_state,
         parse_palette_update,
@@ -260,7 +299,7 @@ pub(crate) fn decode_pcs(input: &mut &Bytes) -> ModalResult<PresentationComposit
             |(
                 width,
                 height,
-                _,
+                frame_rate,
                 comp_no,
                 comp_state,
                 palette_update,
@@ -272,6 +311,7 @@ pub(crate) fn decode_pcs(input: &mut &Bytes) -> ModalResult<PresentationComposit
                     comp_state,
                     width,
                     height,
+                    frame_rate,
                     palette_id,
                     palette_update,
                     composition_objects,
@@ -301,6 +341,7 @@ mod tests {
 
         assert_eq!(1920, pcs.width);
         assert_eq!(1080, pcs.height);
+        assert_eq!(0x10, pcs.frame_rate);
 
         assert_eq!(0, pcs.palette_id);
         assert!(!pcs.palette_update);
@@ -308,7 +349,8 @@ mod tests {
         let obj = &pcs.composition_objects[0];
         assert_eq!(0, obj.id);
         assert_eq!(0, obj.window_id);
-        assert!(obj.cropped);
+        assert!(!obj.cropped);
+        assert!(obj.forced);
         assert_eq!(588, obj.x);
         assert_eq!(868, obj.y);
         assert_eq!(None, obj.crop_x);
@@ -319,7 +361,7 @@ mod tests {
 
     #[test]
     fn composition_object_allows_missing_crop_rect() {
-        let data = hex!("00 00 00 40 02 4c 03 64");
+        let data = hex!("00 00 00 80 02 4c 03 64");
         let obj = parse_composition_object(&mut Bytes::new(&data)).unwrap();
 
         assert_eq!(0, obj.id);
@@ -332,4 +374,42 @@ mod tests {
         assert_eq!(None, obj.crop_width);
         assert_eq!(None, obj.crop_height);
     }
+
+    #[test]
+    fn forced_object_has_no_crop_rect() {
+        let data = hex!("00 01 00 40 02 4c 03 64  00 02 00 00 00 00 00 00");
+        let mut input = Bytes::new(&data);
+        let obj = parse_composition_object(&mut input).unwrap();
+
+        assert_eq!(1, obj.id);
+        assert!(obj.forced);
+        assert!(!obj.cropped);
+        assert_eq!(None, obj.crop_rect());
+
+        // the following object is left untouched
+        assert_eq!(8, input.len());
+    }
+
+    #[test]
+    fn cropped_and_forced_flags_combine() {
+        let data = hex!("00 01 00 c0 02 4c 03 64  00 10 00 20 00 30 00 40");
+        let obj = parse_composition_object(&mut Bytes::new(&data)).unwrap();
+
+        assert!(obj.forced);
+        assert!(obj.cropped);
+        assert_eq!(
+            Some(Rect {
+                x: 0x10,
+                y: 0x20,
+                width: 0x30,
+                height: 0x40,
+            }),
+            obj.crop_rect(),
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_object_flags() {
+        parse_object_flags(&mut Bytes::new(&[0x20])).unwrap_err();
+    }
 }
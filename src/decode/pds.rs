@@ -1,18 +1,69 @@
 use std::fmt;
 
-use winnow::{ModalResult, binary::be_u8, combinator::repeat, prelude::*};
+use winnow::{
+    Bytes, ModalResult, binary::be_u8, combinator::repeat, error::StrContext, prelude::*,
+};
 
-#[derive(Clone)]
-pub(crate) struct PaletteDefinition {
-    pub(crate) id: u8,
-    pub(crate) version: u8,
-    pub(crate) entries: Vec<PaletteEntry>,
+use super::rle::RgbaLut;
+use crate::{Error, ErrorKind, segment::HEADER_LEN};
+
+// Name                Bytes    Description
+// Palette ID          1        ID of the palette
+// Palette Version     1        Version of this palette within the epoch
+// Palette Entry       5 * N    Entry ID, Y, Cr, Cb and alpha of each colour
+
+/// Bytes before the first palette entry.
+const PDS_HEADER_LEN: usize = 2;
+const ENTRY_LEN: usize = 5;
+
+/// Palette Definition Segment
+#[derive(Clone, PartialEq)]
+pub struct PaletteDefinition {
+    pub id: u8,
+    pub version: u8,
+    pub entries: Vec<PaletteEntry>,
 }
 
 impl PaletteDefinition {
-    pub(crate) fn find_by_id(&self, id: u8) -> Option<&PaletteEntry> {
+    pub fn find_by_id(&self, id: u8) -> Option<&PaletteEntry> {
         self.entries.iter().find(|entry| entry.id == id)
     }
+
+    /// Builds a lookup table from palette index to 8-bit RGBA, for
+    /// [`Object::decode_rgba_into`](super::object::Object::decode_rgba_into).
+    ///
+    /// Indices this palette does not define are fully transparent.
+    pub fn rgba_lut(&self) -> RgbaLut {
+        let mut lut = [[0; 4]; 256];
+
+        for entry in &self.entries {
+            lut[usize::from(entry.id)] =
+                entry.rgba().map(|channel| (channel * 255.0).round() as u8);
+        }
+
+        lut
+    }
+
+    /// Applies a later definition of the same palette.
+    ///
+    /// A PDS does not have to redefine every entry of a palette; entries it omits keep their
+    /// previous colour, and the palette takes on the version of `update`.
+    pub fn apply_update(&mut self, update: PaletteDefinition) {
+        debug_assert_eq!(self.id, update.id);
+
+        self.version = update.version;
+
+        for entry in update.entries {
+            match self
+                .entries
+                .iter_mut()
+                .find(|existing| existing.id == entry.id)
+            {
+                Some(existing) => *existing = entry,
+                None => self.entries.push(entry),
+            }
+        }
+    }
 }
 
 impl fmt::Debug for PaletteDefinition {
@@ -37,13 +88,14 @@ impl fmt::Debug for PaletteDefinition {
     }
 }
 
-#[derive(Clone)]
-pub(crate) struct PaletteEntry {
-    pub(crate) id: u8,
-    pub(crate) y: u8,     // (Y) Luminance
-    pub(crate) cr: u8,    // (Cr) Color Difference Red
-    pub(crate) cb: u8,    // (Cb) Color Difference Blue
-    pub(crate) alpha: u8, // Transparency
+/// A single palette colour, stored as YCrCb plus alpha.
+#[derive(Clone, PartialEq)]
+pub struct PaletteEntry {
+    pub id: u8,
+    pub y: u8,     // (Y) Luminance
+    pub cr: u8,    // (Cr) Color Difference Red
+    pub cb: u8,    // (Cb) Color Difference Blue
+    pub alpha: u8, // Transparency
 }
 
 impl PaletteEntry {
@@ -57,7 +109,26 @@ impl PaletteEntry {
         }
     }
 
-    pub(crate) fn rgba(&self) -> [f32; 4] {
+    /// Converts an 8-bit RGBA colour to an entry using BT.601 coefficients, the inverse of
+    /// [`rgba`](Self::rgba) up to rounding.
+    pub fn from_rgba(id: u8, [r, g, b, alpha]: [u8; 4]) -> Self {
+        let (r, g, b) = (f32::from(r), f32::from(g), f32::from(b));
+
+        let y = 16.0 + 0.257 * r + 0.504 * g + 0.098 * b;
+        let cb = 128.0 - 0.148 * r - 0.291 * g + 0.439 * b;
+        let cr = 128.0 + 0.439 * r - 0.368 * g - 0.071 * b;
+
+        Self {
+            id,
+            y: y.round() as u8,
+            cr: cr.round() as u8,
+            cb: cb.round() as u8,
+            alpha,
+        }
+    }
+
+    /// Converts this entry to normalised RGBA using BT.601 coefficients.
+    pub fn rgba(&self) -> [f32; 4] {
         let Self {
             id: _,
             y,
@@ -100,22 +171,63 @@ impl fmt::Debug for PaletteEntry {
     }
 }
 
-fn decode_palette_entry(input: &mut &[u8]) -> ModalResult<PaletteEntry> {
-    (be_u8, be_u8, be_u8, be_u8, be_u8)
+fn decode_palette_entry(input: &mut &Bytes) -> ModalResult<PaletteEntry> {
+    (
+        be_u8.context(StrContext::Label("PDS palette entry id")),
+        be_u8.context(StrContext::Label("PDS palette entry Y")),
+        be_u8.context(StrContext::Label("PDS palette entry Cr")),
+        be_u8.context(StrContext::Label("PDS palette entry Cb")),
+        be_u8.context(StrContext::Label("PDS palette entry alpha")),
+    )
         .map(PaletteEntry::from_tuple)
         .parse_next(input)
 }
 
-pub(crate) fn decode_pds(input: &[u8]) -> PaletteDefinition {
-    let (palette_id, version, entries) = (be_u8, be_u8, repeat(1.., decode_palette_entry))
-        .parse(input)
-        .unwrap();
+/// Checks the invariants the parser cannot express: the payload holds only whole entries and no
+/// entry id is defined twice.
+pub(crate) fn validate_pds(payload: &[u8]) -> Result<(), Error> {
+    // a payload too short for the palette id and version is reported by the parser
+    let Some(entries) = payload.get(PDS_HEADER_LEN..) else {
+        return Ok(());
+    };
+
+    let whole_entries_len = entries.len() / ENTRY_LEN * ENTRY_LEN;
+
+    if whole_entries_len != entries.len() {
+        return Err(Error::new(ErrorKind::InvalidLength {
+            field: "PDS palette entries",
+            expected: whole_entries_len,
+            found: entries.len(),
+        })
+        .at_position(HEADER_LEN + PDS_HEADER_LEN + whole_entries_len));
+    }
+
+    let mut defined = [false; 256];
 
-    PaletteDefinition {
-        id: palette_id,
-        version,
-        entries,
+    for (index, entry) in entries.chunks_exact(ENTRY_LEN).enumerate() {
+        let id = entry[0];
+
+        if std::mem::replace(&mut defined[usize::from(id)], true) {
+            return Err(Error::new(ErrorKind::DuplicatePaletteEntry { id })
+                .at_position(HEADER_LEN + PDS_HEADER_LEN + index * ENTRY_LEN));
+        }
     }
+
+    Ok(())
+}
+
+pub(crate) fn decode_pds(input: &mut &Bytes) -> ModalResult<PaletteDefinition> {
+    (
+        be_u8.context(StrContext::Label("PDS palette id")),
+        be_u8.context(StrContext::Label("PDS version")),
+        repeat(0.., decode_palette_entry).context(StrContext::Label("PDS palette entries")),
+    )
+        .map(|(id, version, entries)| PaletteDefinition {
+            id,
+            version,
+            entries,
+        })
+        .parse_next(input)
 }
 
 #[cfg(test)]
@@ -128,7 +240,7 @@ mod tests {
         // strip segment header
         let data = &data[13..];
 
-        let pds = decode_pds(data);
+        let pds = decode_pds.parse(Bytes::new(data)).unwrap();
 
         assert_eq!(pds.id, 0);
         assert_eq!(pds.version, 0);
@@ -137,10 +249,123 @@ mod tests {
         let data = std::fs::read("data/test/pds2.dat").unwrap();
         let data = &data[13..];
 
-        let pds = decode_pds(data);
+        let pds = decode_pds.parse(Bytes::new(data)).unwrap();
 
         assert_eq!(pds.id, 0);
         assert_eq!(pds.version, 0);
         assert_eq!(pds.entries.len(), 29);
     }
+
+    fn pds_segment(payload: &[u8]) -> Vec<u8> {
+        let mut segment = vec![0x50, 0x47, 0, 0, 0, 0, 0, 0, 0, 0, 0x14];
+        segment.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        segment.extend_from_slice(payload);
+        segment
+    }
+
+    #[test]
+    fn rejects_partial_entries() {
+        let data = pds_segment(&[0, 0, 1, 16, 128, 128, 255, 2, 16]);
+        let err = crate::parse_segment(&mut data.as_slice()).unwrap_err();
+
+        assert!(matches!(
+            err.kind(),
+            ErrorKind::InvalidLength {
+                field: "PDS palette entries",
+                expected: 5,
+                found: 7,
+            }
+        ));
+        assert_eq!(Some(HEADER_LEN + 7), err.offset());
+
+        let data = pds_segment(&[0]);
+        let err = crate::parse_segment(&mut data.as_slice()).unwrap_err();
+
+        assert!(matches!(
+            err.kind(),
+            ErrorKind::Truncated {
+                field: "PDS version"
+            }
+        ));
+    }
+
+    #[test]
+    fn rejects_duplicate_entry_ids() {
+        let data = pds_segment(&[
+            0, 0, 1, 16, 128, 128, 255, 2, 16, 128, 128, 0, 1, 0, 0, 0, 0,
+        ]);
+        let err = crate::parse_segment(&mut data.as_slice()).unwrap_err();
+
+        assert!(matches!(
+            err.kind(),
+            ErrorKind::DuplicatePaletteEntry { id: 1 }
+        ));
+        assert_eq!(Some(HEADER_LEN + 12), err.offset());
+    }
+
+    #[test]
+    fn partial_update_keeps_omitted_entries() {
+        let mut palette = PaletteDefinition {
+            id: 2,
+            version: 0,
+            entries: vec![
+                PaletteEntry::from_tuple((1, 16, 128, 128, 255)),
+                PaletteEntry::from_tuple((2, 235, 128, 128, 255)),
+            ],
+        };
+
+        palette.apply_update(PaletteDefinition {
+            id: 2,
+            version: 1,
+            entries: vec![
+                PaletteEntry::from_tuple((2, 235, 128, 128, 64)),
+                PaletteEntry::from_tuple((3, 81, 90, 240, 255)),
+            ],
+        });
+
+        assert_eq!(1, palette.version);
+        assert_eq!(3, palette.entries.len());
+        assert_eq!(255, palette.find_by_id(1).unwrap().alpha);
+        assert_eq!(64, palette.find_by_id(2).unwrap().alpha);
+        assert_eq!(81, palette.find_by_id(3).unwrap().y);
+    }
+
+    #[test]
+    fn rgba_lut_leaves_undefined_entries_transparent() {
+        let palette = PaletteDefinition {
+            id: 0,
+            version: 0,
+            entries: vec![
+                PaletteEntry::from_tuple((1, 235, 128, 128, 255)),
+                PaletteEntry::from_tuple((7, 16, 128, 128, 64)),
+            ],
+        };
+
+        let lut = palette.rgba_lut();
+
+        assert_eq!([0, 0, 0, 0], lut[0]);
+        assert_eq!([255, 255, 255, 255], lut[1]);
+        assert_eq!([0, 0, 0, 64], lut[7]);
+        assert_eq!([0, 0, 0, 0], lut[255]);
+    }
+
+    #[test]
+    fn rgba_conversion_round_trips() {
+        for color in [
+            [0, 0, 0, 255],
+            [255, 255, 255, 255],
+            [255, 0, 0, 128],
+            [0, 255, 0, 64],
+            [0, 0, 255, 1],
+            [200, 180, 30, 255],
+        ] {
+            let rgba = PaletteEntry::from_rgba(9, color)
+                .rgba()
+                .map(|channel| (channel * 255.0).round() as u8);
+
+            for (expected, found) in color.into_iter().zip(rgba) {
+                assert!(expected.abs_diff(found) <= 2, "{color:?} became {rgba:?}");
+            }
+        }
+    }
 }
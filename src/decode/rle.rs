@@ -1,4 +1,9 @@
-use winnow::{Bytes, ModalResult, binary::be_u8, error::StrContext, prelude::*};
+//! Run-length decoding of object bitmaps.
+
+#[cfg(test)]
+mod reference;
+
+use crate::{Error, ErrorKind};
 
 // The Run-length encoding method is defined in the US 7912305 B1 patent.
 // Here’s a quick and dirty definition to this method:
@@ -12,165 +17,458 @@ use winnow::{Bytes, ModalResult, binary::be_u8, error::StrContext, prelude::*};
 
 const COLOR_BLACK: u8 = 0;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum RleChunk {
-    Eol,
-    Run { len: usize, color: u8 },
-    Pixel(u8),
+/// How the decoder treats RLE data that does not match its object's dimensions.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RleMode {
+    /// Reject the object.
+    #[default]
+    Strict,
+
+    /// Pad short lines and missing lines with color 0, drop excess pixels and lines, and log a
+    /// warning for each repair.
+    Lenient,
 }
 
-impl RleChunk {
-    fn append_to(self, output: &mut Vec<u8>) {
-        match self {
-            Self::Eol => {}
-            Self::Run { len, color } => output.resize(output.len() + len, color),
-            Self::Pixel(pixel) => output.push(pixel),
-        }
+/// Palette index to RGBA lookup table, as built by
+/// [`PaletteDefinition::rgba_lut`](super::pds::PaletteDefinition::rgba_lut).
+pub type RgbaLut = [[u8; 4]; 256];
+
+/// Destination of decoded pixels, which arrive row by row.
+trait PixelSink {
+    /// Appends `len` pixels of palette index `color`.
+    fn fill(&mut self, color: u8, len: usize);
+
+    /// Appends one pixel per palette index in `colors`.
+    fn copy(&mut self, colors: &[u8]);
+}
+
+/// Drops every pixel, for validation.
+struct Discard;
+
+impl PixelSink for Discard {
+    fn fill(&mut self, _color: u8, _len: usize) {}
+
+    fn copy(&mut self, _colors: &[u8]) {}
+}
+
+/// Writes palette indices into a buffer of exactly one byte per pixel.
+struct Indices<'b> {
+    buf: &'b mut [u8],
+    pos: usize,
+}
+
+impl PixelSink for Indices<'_> {
+    fn fill(&mut self, color: u8, len: usize) {
+        self.buf[self.pos..self.pos + len].fill(color);
+        self.pos += len;
     }
 
-    fn into_vec(self) -> Vec<u8> {
-        let mut output = Vec::new();
-        self.append_to(&mut output);
-        output
+    fn copy(&mut self, colors: &[u8]) {
+        self.buf[self.pos..self.pos + colors.len()].copy_from_slice(colors);
+        self.pos += colors.len();
     }
 }
 
-fn decode_rle_chunk(input: &mut &Bytes) -> ModalResult<RleChunk> {
-    let first = be_u8.parse_next(input)?;
+/// Writes RGBA colours looked up in a palette into a buffer of exactly one colour per pixel.
+struct Rgba<'b> {
+    buf: &'b mut [[u8; 4]],
+    lut: &'b RgbaLut,
+    pos: usize,
+}
 
-    if first != 0 {
-        return Ok(RleChunk::Pixel(first));
+impl PixelSink for Rgba<'_> {
+    fn fill(&mut self, color: u8, len: usize) {
+        self.buf[self.pos..self.pos + len].fill(self.lut[usize::from(color)]);
+        self.pos += len;
     }
 
-    let info = be_u8.parse_next(input)?;
+    fn copy(&mut self, colors: &[u8]) {
+        let pixels = &mut self.buf[self.pos..self.pos + colors.len()];
+
+        for (pixel, &color) in pixels.iter_mut().zip(colors) {
+            *pixel = self.lut[usize::from(color)];
+        }
 
-    if info == 0 {
-        return Ok(RleChunk::Eol);
+        self.pos += colors.len();
     }
+}
 
-    let is_color = info & 0b1000_0000 != 0;
-    let is_long = info & 0b0100_0000 != 0;
-    let len_hi = usize::from(info & 0b0011_1111);
+/// Decodes the RLE data of a `width` by `height` object into one palette index per pixel.
+///
+/// Every line must hold exactly `width` pixels and end with an end-of-line marker, and there must
+/// be exactly `height` lines. Pixels beyond the object are never stored, so the output is always
+/// `width * height` long, whatever the input claims.
+///
+/// The data is checked before the output is allocated, so in strict mode an object whose
+/// dimensions are far larger than its data can describe never costs more than the walk.
+pub(crate) fn decode_rle_stream(
+    data: &[u8],
+    width: u16,
+    height: u16,
+    mode: RleMode,
+) -> Result<Vec<u8>, Error> {
+    validate_rle(data, width, height, mode)?;
 
-    let len = if is_long {
-        (len_hi << 8) | usize::from(be_u8.parse_next(input)?)
-    } else {
-        len_hi
-    };
+    let mut output = vec![0; usize::from(width) * usize::from(height)];
+    decode_validated_rle(data, width, height, &mut output);
+    Ok(output)
+}
 
-    let color = if is_color {
-        be_u8.parse_next(input)?
-    } else {
-        COLOR_BLACK
-    };
+/// Checks RLE data like [`decode_rle_stream`] without storing any pixels.
+pub(crate) fn validate_rle(
+    data: &[u8],
+    width: u16,
+    height: u16,
+    mode: RleMode,
+) -> Result<(), Error> {
+    walk_rle(data, width, height, mode_check(mode), &mut Discard)
+}
 
-    Ok(RleChunk::Run { len, color })
+/// Decodes RLE data that already passed [`validate_rle`] into `buf`, which holds one palette
+/// index per pixel, repeating any repairs silently.
+pub(crate) fn decode_validated_rle(data: &[u8], width: u16, height: u16, buf: &mut [u8]) {
+    let mut sink = Indices { buf, pos: 0 };
+    walk_rle(data, width, height, |_| Ok(()), &mut sink)
+        .expect("RLE data should have been validated");
 }
 
-#[cfg_attr(not(test), expect(dead_code))]
-pub(crate) fn decode_rle(input: &mut &Bytes) -> ModalResult<Vec<u8>> {
-    decode_rle_chunk
-        .context(StrContext::Label("RLE chunk"))
-        .map(RleChunk::into_vec)
-        .parse_next(input)
+/// Like [`decode_validated_rle`], but writes the colour of each pixel from `lut` into `buf`, which
+/// holds 4 bytes per pixel.
+pub(crate) fn decode_validated_rle_rgba(
+    data: &[u8],
+    width: u16,
+    height: u16,
+    lut: &RgbaLut,
+    buf: &mut [u8],
+) {
+    let (buf, _) = buf.as_chunks_mut::<4>();
+    let mut sink = Rgba { buf, lut, pos: 0 };
+    walk_rle(data, width, height, |_| Ok(()), &mut sink)
+        .expect("RLE data should have been validated");
 }
 
-pub(crate) fn decode_rle_stream(
-    input: &mut &Bytes,
-    expected_pixels: usize,
-) -> ModalResult<Vec<u8>> {
-    let mut output = Vec::with_capacity(expected_pixels);
+fn mode_check(mode: RleMode) -> impl FnMut(ErrorKind) -> Result<(), Error> {
+    move |problem| match mode {
+        RleMode::Strict => Err(Error::new(problem)),
+        RleMode::Lenient => {
+            tracing::warn!("repairing RLE data: {problem}");
+            Ok(())
+        }
+    }
+}
+
+/// Walks RLE data line by line, passing each problem to `check` and emitting exactly
+/// `width * height` pixels to `sink`.
+///
+/// This is the hot path of bitmap decoding: every code is bounds checked once, runs of literal
+/// pixels are copied in bulk, and colour runs become a single fill.
+fn walk_rle(
+    data: &[u8],
+    width: u16,
+    height: u16,
+    mut check: impl FnMut(ErrorKind) -> Result<(), Error>,
+    sink: &mut impl PixelSink,
+) -> Result<(), Error> {
+    let width = usize::from(width);
+    let height = usize::from(height);
+
+    let mut pos = 0;
+    let mut stored = 0;
+    let mut line = 0;
+    let mut line_len = 0;
+    let mut excess_pixels = 0;
+
+    let truncated = || {
+        Error::new(ErrorKind::Truncated {
+            field: "ODS RLE data",
+        })
+    };
+
+    while let Some(&first) = data.get(pos) {
+        if first != 0 {
+            // literal pixels run until the next escape byte
+            let rest = &data[pos..];
+            let literals = rest
+                .iter()
+                .position(|&byte| byte == 0)
+                .unwrap_or(rest.len());
+
+            if line < height {
+                let kept = literals.min(width.saturating_sub(line_len));
+                sink.copy(&rest[..kept]);
+                stored += kept;
+            } else {
+                excess_pixels += literals;
+            }
+
+            pos += literals;
+            line_len += literals;
+            continue;
+        }
+
+        let Some(&info) = data.get(pos + 1) else {
+            return Err(truncated());
+        };
+
+        let code_len = match info >> 6 {
+            0b00 => 2,
+            0b01 | 0b10 => 3,
+            _ => 4,
+        };
+        let Some(code) = data.get(pos..pos + code_len) else {
+            return Err(truncated());
+        };
+        pos += code_len;
+
+        let len_hi = usize::from(info & 0b0011_1111);
+        let (len, color) = match info >> 6 {
+            0b00 if len_hi == 0 => {
+                if line < height {
+                    if line_len != width {
+                        check(ErrorKind::RleLineWidth {
+                            line,
+                            expected: width,
+                            found: line_len,
+                        })?;
+                    }
+
+                    pad_to(sink, &mut stored, (line + 1) * width);
+                }
+
+                line += 1;
+                line_len = 0;
+                continue;
+            }
+            0b00 => (len_hi, COLOR_BLACK),
+            0b01 => ((len_hi << 8) | usize::from(code[2]), COLOR_BLACK),
+            0b10 => (len_hi, code[2]),
+            _ => ((len_hi << 8) | usize::from(code[2]), code[3]),
+        };
+
+        if len == 0 {
+            check(ErrorKind::RleZeroLengthRun { line })?;
+            continue;
+        }
+
+        if line < height {
+            let kept = len.min(width.saturating_sub(line_len));
+
+            if kept > 0 {
+                sink.fill(color, kept);
+                stored += kept;
+            }
+        } else {
+            excess_pixels += len;
+        }
 
-    while !input.is_empty() {
-        decode_rle_chunk.parse_next(input)?.append_to(&mut output);
+        line_len += len;
     }
 
-    Ok(output)
+    if line_len > 0 {
+        check(ErrorKind::RleMissingEol { line })?;
+
+        if line < height {
+            pad_to(sink, &mut stored, (line + 1) * width);
+        }
+
+        line += 1;
+    }
+
+    if excess_pixels > 0 {
+        check(ErrorKind::RleOverrun {
+            expected: width * height,
+            decoded: width * height + excess_pixels,
+        })?;
+    } else if line != height {
+        check(ErrorKind::RleLineCount {
+            expected: height,
+            found: line,
+        })?;
+    }
+
+    pad_to(sink, &mut stored, width * height);
+
+    Ok(())
+}
+
+/// Fills with color 0 up to pixel `end`, for short lines and missing lines.
+fn pad_to(sink: &mut impl PixelSink, stored: &mut usize, end: usize) {
+    if end > *stored {
+        sink.fill(COLOR_BLACK, end - *stored);
+        *stored = end;
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    #[test]
-    fn parses_eol() {
-        assert_eq!(
-            Ok((Bytes::new(&[]), RleChunk::Eol)),
-            decode_rle_chunk.parse_peek(Bytes::new(&[0, 0])),
-        );
+    fn decode(data: &[u8], mode: RleMode) -> Result<Vec<u8>, Error> {
+        let decoded = decode_rle_stream(data, 3, 2, mode);
+        let expected = reference::decode_rle_stream(data, 3, 2, mode);
         assert_eq!(
-            Ok((Bytes::new(&[0]), RleChunk::Eol)),
-            decode_rle_chunk.parse_peek(Bytes::new(&[0, 0, 0])),
+            expected.as_ref().map_err(ToString::to_string),
+            decoded.as_ref().map_err(ToString::to_string),
         );
+        decoded
+    }
+
+    #[test]
+    fn decodes_lines_of_object() {
+        let data = [0, 0x83, 5, 0, 0, 1, 0, 2, 0, 0];
+
         assert_eq!(
-            Ok((Bytes::new(&[]), Vec::new())),
-            decode_rle.parse_peek(Bytes::new(&[0, 0])),
+            vec![5, 5, 5, 1, 0, 0],
+            decode(&data, RleMode::Strict).unwrap()
         );
+    }
+
+    #[test]
+    fn rejects_lines_of_wrong_width() {
+        let err = decode(&[0, 0x84, 5, 0, 0, 1, 1, 1, 0, 0], RleMode::Strict).unwrap_err();
+        assert!(matches!(
+            err.kind(),
+            ErrorKind::RleLineWidth {
+                line: 0,
+                expected: 3,
+                found: 4,
+            }
+        ));
 
-        decode_rle.parse_peek(Bytes::new(&[0])).unwrap_err();
+        let err = decode(&[1, 1, 1, 0, 0, 1, 0, 0], RleMode::Strict).unwrap_err();
+        assert!(matches!(
+            err.kind(),
+            ErrorKind::RleLineWidth {
+                line: 1,
+                expected: 3,
+                found: 1,
+            }
+        ));
     }
 
     #[test]
-    fn single_pixel() {
-        assert_eq!(
-            Ok((Bytes::new(&[]), RleChunk::Pixel(1))),
-            decode_rle_chunk.parse_peek(Bytes::new(&[0b0000_0001])),
-        );
+    fn rejects_missing_and_extra_eols() {
+        let err = decode(&[1, 1, 1, 0, 0, 2, 2, 2], RleMode::Strict).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::RleMissingEol { line: 1 }));
+
+        let err = decode(&[1, 1, 1, 0, 0], RleMode::Strict).unwrap_err();
+        assert!(matches!(
+            err.kind(),
+            ErrorKind::RleLineCount {
+                expected: 2,
+                found: 1,
+            }
+        ));
+
+        let err = decode(&[1, 1, 1, 0, 0, 2, 2, 2, 0, 0, 0, 0], RleMode::Strict).unwrap_err();
+        assert!(matches!(
+            err.kind(),
+            ErrorKind::RleLineCount {
+                expected: 2,
+                found: 3,
+            }
+        ));
+
+        let err = decode(&[1, 1, 1, 0, 0, 2, 2, 2, 0, 0, 3, 0, 0], RleMode::Strict).unwrap_err();
+        assert!(matches!(
+            err.kind(),
+            ErrorKind::RleOverrun {
+                expected: 6,
+                decoded: 7,
+            }
+        ));
     }
 
     #[test]
-    fn short_black_pixels() {
-        assert_eq!(
-            Ok((
-                Bytes::new(&[]),
-                RleChunk::Run {
-                    len: 5,
-                    color: COLOR_BLACK,
-                },
-            )),
-            decode_rle_chunk.parse_peek(Bytes::new(&[0, 0b0000_0101])),
-        );
+    fn rejects_zero_length_runs() {
+        let data = [1, 1, 1, 0, 0, 0, 0x80, 4, 2, 2, 2, 0, 0];
+
+        let err = decode(&data, RleMode::Strict).unwrap_err();
+        assert!(matches!(
+            err.kind(),
+            ErrorKind::RleZeroLengthRun { line: 1 }
+        ));
+
         assert_eq!(
-            Ok((Bytes::new(&[]), vec![0; 5])),
-            decode_rle.parse_peek(Bytes::new(&[0, 0b0000_0101])),
+            vec![1, 1, 1, 2, 2, 2],
+            decode(&data, RleMode::Lenient).unwrap()
         );
     }
 
     #[test]
-    fn long_black_pixels() {
+    fn lenient_mode_pads_and_truncates_lines() {
+        // first line too long, second too short, then an extra line
+        let data = [0, 0x84, 5, 0, 0, 1, 0, 0, 2, 2, 0, 0];
         assert_eq!(
-            Ok((Bytes::new(&[]), vec![0u8; 32])),
-            decode_rle.parse_peek(Bytes::new(&[0, 0b0100_0000, 0b0010_0000])),
+            vec![5, 5, 5, 1, 0, 0],
+            decode(&data, RleMode::Lenient).unwrap()
         );
-    }
 
-    #[test]
-    fn short_color_pixels() {
+        // only one line, without its end-of-line marker
         assert_eq!(
-            Ok((Bytes::new(&[]), vec![0b0000_0001; 5])),
-            decode_rle.parse_peek(Bytes::new(&[0, 0b1000_0101, 0b0000_0001])),
+            vec![1, 0, 0, 0, 0, 0],
+            decode(&[1], RleMode::Lenient).unwrap()
         );
     }
 
     #[test]
-    fn long_color_pixels() {
-        assert_eq!(
-            Ok((Bytes::new(&[]), vec![1u8; 32])),
-            decode_rle.parse_peek(Bytes::new(&[0, 0b1100_0000, 0b0010_0000, 0b0000_0001])),
-        );
+    fn matches_reference_decoder_on_samples() {
+        for file in ["data/small.sup", "data/mummyforced.sup"] {
+            let bytes = std::fs::read(file).unwrap();
+
+            for segment in crate::raw::segments(&bytes) {
+                let Some(fragment) = segment.unwrap().object_fragment() else {
+                    continue;
+                };
+                let fragment = fragment.unwrap();
+                let (data, width, height) = (fragment.data, fragment.width, fragment.height);
+
+                assert_eq!(
+                    reference::decode_rle_stream(data, width, height, RleMode::Strict).unwrap(),
+                    decode_rle_stream(data, width, height, RleMode::Strict).unwrap(),
+                );
+
+                // damage the data in a few places and check both decoders agree on the outcome
+                for start in (0..data.len()).step_by(data.len() / 16 + 1) {
+                    let mut damaged = data.to_vec();
+                    damaged[start] ^= 0xa5;
+                    damaged.truncate(data.len() - start % 7);
+
+                    for mode in [RleMode::Strict, RleMode::Lenient] {
+                        let expected = reference::decode_rle_stream(&damaged, width, height, mode);
+                        let decoded = decode_rle_stream(&damaged, width, height, mode);
+
+                        assert_eq!(
+                            expected.map_err(|err| err.to_string()),
+                            decoded.map_err(|err| err.to_string()),
+                        );
+                    }
+                }
+            }
+        }
     }
 
     #[test]
-    fn rejects_truncated_sequences() {
-        decode_rle.parse_peek(Bytes::new(&[0])).unwrap_err();
-        decode_rle
-            .parse_peek(Bytes::new(&[0, 0b0100_0000]))
-            .unwrap_err();
-        decode_rle
-            .parse_peek(Bytes::new(&[0, 0b1000_0101]))
-            .unwrap_err();
-        decode_rle
-            .parse_peek(Bytes::new(&[0, 0b1100_0000, 0b0010_0000]))
-            .unwrap_err();
+    fn decodes_straight_to_rgba() {
+        let data = [0, 0x83, 5, 0, 0, 1, 0, 2, 0, 0];
+        let mut lut = [[0; 4]; 256];
+        lut[1] = [1, 1, 1, 255];
+        lut[5] = [5, 5, 5, 128];
+
+        let mut rgba = [0xff; 3 * 2 * 4];
+        decode_validated_rle_rgba(&data, 3, 2, &lut, &mut rgba);
+
+        assert_eq!(
+            [
+                [5, 5, 5, 128],
+                [5, 5, 5, 128],
+                [5, 5, 5, 128],
+                [1, 1, 1, 255],
+                [0, 0, 0, 0],
+                [0, 0, 0, 0],
+            ]
+            .as_flattened(),
+            rgba,
+        );
     }
 }
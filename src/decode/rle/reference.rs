@@ -0,0 +1,257 @@
+//! Reference RLE decoder built from winnow parsers, kept to test the optimised decoder against.
+
+use winnow::{Bytes, ModalResult, binary::be_u8, error::StrContext, prelude::*};
+
+use super::{COLOR_BLACK, RleMode, mode_check};
+use crate::{Error, ErrorKind};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RleChunk {
+    Eol,
+    Run { len: usize, color: u8 },
+    Pixel(u8),
+}
+
+impl RleChunk {
+    fn append_to(self, output: &mut Vec<u8>) {
+        match self {
+            Self::Eol => {}
+            Self::Run { len, color } => output.resize(output.len() + len, color),
+            Self::Pixel(pixel) => output.push(pixel),
+        }
+    }
+
+    fn into_vec(self) -> Vec<u8> {
+        let mut output = Vec::new();
+        self.append_to(&mut output);
+        output
+    }
+}
+
+fn decode_rle_chunk(input: &mut &Bytes) -> ModalResult<RleChunk> {
+    let first = be_u8.parse_next(input)?;
+
+    if first != 0 {
+        return Ok(RleChunk::Pixel(first));
+    }
+
+    let info = be_u8.parse_next(input)?;
+
+    if info == 0 {
+        return Ok(RleChunk::Eol);
+    }
+
+    let is_color = info & 0b1000_0000 != 0;
+    let is_long = info & 0b0100_0000 != 0;
+    let len_hi = usize::from(info & 0b0011_1111);
+
+    let len = if is_long {
+        (len_hi << 8) | usize::from(be_u8.parse_next(input)?)
+    } else {
+        len_hi
+    };
+
+    let color = if is_color {
+        be_u8.parse_next(input)?
+    } else {
+        COLOR_BLACK
+    };
+
+    Ok(RleChunk::Run { len, color })
+}
+
+fn decode_rle(input: &mut &Bytes) -> ModalResult<Vec<u8>> {
+    decode_rle_chunk
+        .context(StrContext::Label("RLE chunk"))
+        .map(RleChunk::into_vec)
+        .parse_next(input)
+}
+
+/// Straightforward decoder the optimised one in the parent module is checked against.
+pub(super) fn decode_rle_stream(
+    data: &[u8],
+    width: u16,
+    height: u16,
+    mode: RleMode,
+) -> Result<Vec<u8>, Error> {
+    let input = &mut Bytes::new(data);
+    let mut check = mode_check(mode);
+    let mut output = Vec::new();
+    let mut fill = |color, len| output.resize(output.len() + len, color);
+
+    let width = usize::from(width);
+    let height = usize::from(height);
+
+    let mut stored = 0;
+    let mut line = 0;
+    let mut line_len = 0;
+    let mut excess_pixels = 0;
+
+    while !input.is_empty() {
+        let chunk = decode_rle_chunk.parse_next(input).map_err(|_| {
+            Error::new(ErrorKind::Truncated {
+                field: "ODS RLE data",
+            })
+        })?;
+
+        let (len, color) = match chunk {
+            RleChunk::Eol => {
+                if line < height {
+                    if line_len != width {
+                        check(ErrorKind::RleLineWidth {
+                            line,
+                            expected: width,
+                            found: line_len,
+                        })?;
+                    }
+
+                    pad_to(&mut fill, &mut stored, (line + 1) * width);
+                }
+
+                line += 1;
+                line_len = 0;
+                continue;
+            }
+            RleChunk::Run { len: 0, .. } => {
+                check(ErrorKind::RleZeroLengthRun { line })?;
+                continue;
+            }
+            RleChunk::Run { len, color } => (len, color),
+            RleChunk::Pixel(color) => (1, color),
+        };
+
+        if line < height {
+            let kept = len.min(width.saturating_sub(line_len));
+
+            if kept > 0 {
+                fill(color, kept);
+                stored += kept;
+            }
+        } else {
+            excess_pixels += len;
+        }
+
+        line_len += len;
+    }
+
+    if line_len > 0 {
+        check(ErrorKind::RleMissingEol { line })?;
+
+        if line < height {
+            pad_to(&mut fill, &mut stored, (line + 1) * width);
+        }
+
+        line += 1;
+    }
+
+    if excess_pixels > 0 {
+        check(ErrorKind::RleOverrun {
+            expected: width * height,
+            decoded: width * height + excess_pixels,
+        })?;
+    } else if line != height {
+        check(ErrorKind::RleLineCount {
+            expected: height,
+            found: line,
+        })?;
+    }
+
+    pad_to(&mut fill, &mut stored, width * height);
+
+    Ok(output)
+}
+
+/// Fills with color 0 up to pixel `end`, for short lines and missing lines.
+fn pad_to(fill: &mut impl FnMut(u8, usize), stored: &mut usize, end: usize) {
+    if end > *stored {
+        fill(COLOR_BLACK, end - *stored);
+        *stored = end;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_eol() {
+        assert_eq!(
+            Ok((Bytes::new(&[]), RleChunk::Eol)),
+            decode_rle_chunk.parse_peek(Bytes::new(&[0, 0])),
+        );
+        assert_eq!(
+            Ok((Bytes::new(&[0]), RleChunk::Eol)),
+            decode_rle_chunk.parse_peek(Bytes::new(&[0, 0, 0])),
+        );
+        assert_eq!(
+            Ok((Bytes::new(&[]), Vec::new())),
+            decode_rle.parse_peek(Bytes::new(&[0, 0])),
+        );
+
+        decode_rle.parse_peek(Bytes::new(&[0])).unwrap_err();
+    }
+
+    #[test]
+    fn single_pixel() {
+        assert_eq!(
+            Ok((Bytes::new(&[]), RleChunk::Pixel(1))),
+            decode_rle_chunk.parse_peek(Bytes::new(&[0b0000_0001])),
+        );
+    }
+
+    #[test]
+    fn short_black_pixels() {
+        assert_eq!(
+            Ok((
+                Bytes::new(&[]),
+                RleChunk::Run {
+                    len: 5,
+                    color: COLOR_BLACK,
+                },
+            )),
+            decode_rle_chunk.parse_peek(Bytes::new(&[0, 0b0000_0101])),
+        );
+        assert_eq!(
+            Ok((Bytes::new(&[]), vec![0; 5])),
+            decode_rle.parse_peek(Bytes::new(&[0, 0b0000_0101])),
+        );
+    }
+
+    #[test]
+    fn long_black_pixels() {
+        assert_eq!(
+            Ok((Bytes::new(&[]), vec![0u8; 32])),
+            decode_rle.parse_peek(Bytes::new(&[0, 0b0100_0000, 0b0010_0000])),
+        );
+    }
+
+    #[test]
+    fn short_color_pixels() {
+        assert_eq!(
+            Ok((Bytes::new(&[]), vec![0b0000_0001; 5])),
+            decode_rle.parse_peek(Bytes::new(&[0, 0b1000_0101, 0b0000_0001])),
+        );
+    }
+
+    #[test]
+    fn long_color_pixels() {
+        assert_eq!(
+            Ok((Bytes::new(&[]), vec![1u8; 32])),
+            decode_rle.parse_peek(Bytes::new(&[0, 0b1100_0000, 0b0010_0000, 0b0000_0001])),
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_sequences() {
+        decode_rle.parse_peek(Bytes::new(&[0])).unwrap_err();
+        decode_rle
+            .parse_peek(Bytes::new(&[0, 0b0100_0000]))
+            .unwrap_err();
+        decode_rle
+            .parse_peek(Bytes::new(&[0, 0b1000_0101]))
+            .unwrap_err();
+        decode_rle
+            .parse_peek(Bytes::new(&[0, 0b1100_0000, 0b0010_0000]))
+            .unwrap_err();
+    }
+}
@@ -1,24 +1,43 @@
 use winnow::{
     Bytes, ModalResult,
     binary::{be_u8, be_u16, length_repeat},
+    error::StrContext,
     prelude::*,
 };
 
-#[derive(Debug, Clone)]
-pub(crate) struct WindowDefinition {
-    #[cfg_attr(not(test), expect(dead_code))]
-    id: u8,
-    #[cfg_attr(not(test), expect(dead_code))]
-    x: u16,
-    #[cfg_attr(not(test), expect(dead_code))]
-    y: u16,
-    #[cfg_attr(not(test), expect(dead_code))]
-    width: u16,
-    #[cfg_attr(not(test), expect(dead_code))]
-    height: u16,
+use super::Rect;
+
+// The Window Definition Segment defines the rectangular areas of the screen that objects are drawn
+// into. Objects are clipped to the window they are assigned to.
+// Name                          Bytes    Description
+// Number of Windows             1        Number of windows defined in this segment
+// Window ID                     1        ID of this window
+// Window Horizontal Position    2        X offset from the top left pixel of the window in the screen
+// Window Vertical Position      2        Y offset from the top left pixel of the window in the screen
+// Window Width                  2        Width of the window
+// Window Height                 2        Height of the window
+
+/// Window Definition Segment entry.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WindowDefinition {
+    pub id: u8,
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
 }
 
 impl WindowDefinition {
+    /// Screen area covered by this window.
+    pub fn rect(&self) -> Rect {
+        Rect {
+            x: self.x,
+            y: self.y,
+            width: self.width,
+            height: self.height,
+        }
+    }
+
     fn from_tuple((id, x, y, width, height): (u8, u16, u16, u16, u16)) -> Self {
         Self {
             id,
@@ -31,13 +50,23 @@ impl WindowDefinition {
 }
 
 fn decode_single_window_definiton(input: &mut &Bytes) -> ModalResult<WindowDefinition> {
-    (be_u8, be_u16, be_u16, be_u16, be_u16)
+    (
+        be_u8.context(StrContext::Label("WDS window id")),
+        be_u16.context(StrContext::Label("WDS window x")),
+        be_u16.context(StrContext::Label("WDS window y")),
+        be_u16.context(StrContext::Label("WDS window width")),
+        be_u16.context(StrContext::Label("WDS window height")),
+    )
         .map(WindowDefinition::from_tuple)
         .parse_next(input)
 }
 
 pub(crate) fn decode_wds(input: &mut &Bytes) -> ModalResult<Vec<WindowDefinition>> {
-    length_repeat(be_u8, decode_single_window_definiton).parse_next(input)
+    length_repeat(
+        be_u8.context(StrContext::Label("WDS window count")),
+        decode_single_window_definiton,
+    )
+    .parse_next(input)
 }
 
 #[cfg(test)]
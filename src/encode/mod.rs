@@ -0,0 +1,466 @@
+//! Serialisation of segments and display sets back to PGS bytes.
+//!
+//! [`write_segment`] is the inverse of [`parse_segment`](crate::parse_segment): every field the
+//! decoder keeps is written back as it was read, so a parsed segment encodes to the bytes it was
+//! parsed from. [`write_display_sets`] rebuilds a stream from decoded display sets, which parses
+//! back to the same display sets. New objects and palettes can be authored from RGBA images with
+//! [`encode_rgba`].
+//!
+//! ```no_run
+//! let bytes = std::fs::read("subtitles.sup")?;
+//! let frames = sup_decode::parse_frames(&bytes)?;
+//!
+//! let out = std::io::BufWriter::new(std::fs::File::create("copy.sup")?);
+//! sup_decode::encode::write_display_sets(out, &frames)?;
+//! # Ok::<_, Box<dyn std::error::Error>>(())
+//! ```
+
+mod quantize;
+mod rle;
+
+use std::io::Write;
+
+pub use self::{
+    quantize::{Quantized, encode_rgba, quantize_rgba},
+    rle::encode_rle,
+};
+use crate::{
+    DisplaySet, DisplayUpdate, Error, ErrorKind, Segment, SegmentData, Timestamp,
+    decode::{
+        object::Object,
+        ods::{ObjectDefinition, SequenceFlag},
+        pcs::{
+            CompositionObject, CompositionState, OBJECT_FLAG_CROPPED, OBJECT_FLAG_FORCED,
+            PresentationComposition,
+        },
+        pds::PaletteDefinition,
+        wds::WindowDefinition,
+    },
+    segment::{HEADER_LEN, MAGIC},
+};
+
+/// Largest payload a segment header can describe.
+const MAX_PAYLOAD_LEN: usize = u16::MAX as usize;
+
+/// Largest object data length an ODS can describe, including the 4 bytes of dimensions.
+const MAX_OBJECT_DATA_LEN: usize = 0xFF_FFFF;
+
+/// Bytes before the RLE data in the first fragment of an object: id, version, sequence flag, data
+/// length, width and height.
+const FIRST_FRAGMENT_HEADER_LEN: usize = 11;
+
+/// Bytes before the RLE data in a continuation fragment: id, version and sequence flag.
+const NEXT_FRAGMENT_HEADER_LEN: usize = 4;
+
+/// Appends the encoded segment, header included, to `buf`.
+///
+/// Timestamps are written modulo 2^32 ticks, the width of the header fields. If the payload does
+/// not fit in a segment, `buf` is left unchanged.
+pub fn encode_segment(segment: &Segment, buf: &mut Vec<u8>) -> Result<(), Error> {
+    let start = buf.len();
+
+    buf.extend_from_slice(&MAGIC);
+    buf.extend_from_slice(&timestamp_field(segment.pts));
+    buf.extend_from_slice(&timestamp_field(segment.dts));
+    buf.push(segment.segment_type().to_byte());
+    // payload size, filled in once the payload is written
+    buf.extend_from_slice(&[0, 0]);
+
+    let payload = encode_payload(&segment.data, buf).and_then(|()| {
+        let len = buf.len() - start - HEADER_LEN;
+
+        u16::try_from(len).map_err(|_| {
+            Error::new(ErrorKind::TooLong {
+                field: "PGS segment payload",
+                len,
+                max: MAX_PAYLOAD_LEN,
+            })
+        })
+    });
+
+    match payload {
+        Ok(size) => {
+            buf[start + HEADER_LEN - 2..start + HEADER_LEN].copy_from_slice(&size.to_be_bytes());
+            Ok(())
+        }
+        Err(err) => {
+            buf.truncate(start);
+            Err(err
+                .with_segment_type(segment.segment_type())
+                .with_pts(segment.pts))
+        }
+    }
+}
+
+/// Writes one encoded segment to `writer`.
+pub fn write_segment(mut writer: impl Write, segment: &Segment) -> Result<(), Error> {
+    let mut buf = Vec::new();
+    encode_segment(segment, &mut buf)?;
+    writer.write_all(&buf)?;
+    Ok(())
+}
+
+/// Writes display sets as a PGS stream that parses back to the same display sets.
+///
+/// Each display set is written out in full with [`display_set_segments`]. Wrap unbuffered
+/// destinations like files in a [`BufWriter`](std::io::BufWriter) first.
+pub fn write_display_sets<'a>(
+    mut writer: impl Write,
+    display_sets: impl IntoIterator<Item = &'a DisplaySet>,
+) -> Result<(), Error> {
+    let mut buf = Vec::new();
+
+    for display_set in display_sets {
+        buf.clear();
+
+        for segment in display_set_segments(display_set)? {
+            encode_segment(&segment, &mut buf)?;
+        }
+
+        writer.write_all(&buf)?;
+    }
+
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Rebuilds the segments of a display set, from its PCS to its END segment.
+///
+/// The windows and palette of the display set are always defined again, and so are its objects
+/// unless it is a palette-only update, which reuses the objects already on screen. Every segment
+/// takes the timestamps of the display set.
+pub fn display_set_segments(display_set: &DisplaySet) -> Result<Vec<Segment>, Error> {
+    let segment = |data| Segment {
+        pts: display_set.pts,
+        dts: display_set.dts,
+        data,
+    };
+
+    let mut segments = vec![segment(SegmentData::Pcs(display_set.pcs.clone()))];
+
+    if !display_set.wds.is_empty() {
+        segments.push(segment(SegmentData::Wds(display_set.wds.clone())));
+    }
+
+    if !display_set.pds.entries.is_empty() {
+        segments.push(segment(SegmentData::Pds(display_set.pds.clone())));
+    }
+
+    if display_set.update != DisplayUpdate::PaletteOnly {
+        for object in &display_set.objects {
+            segments.extend(object_fragments(object)?.map(SegmentData::Ods).map(segment));
+        }
+    }
+
+    segments.push(segment(SegmentData::End));
+
+    Ok(segments)
+}
+
+/// Splits an object into as few ODS fragments as its RLE data fits in.
+pub fn object_fragments(
+    object: &Object,
+) -> Result<impl Iterator<Item = ObjectDefinition> + '_, Error> {
+    let rle = object.rle();
+    let data_len = rle.len() + 4;
+
+    if data_len > MAX_OBJECT_DATA_LEN {
+        return Err(Error::new(ErrorKind::TooLong {
+            field: "ODS object data",
+            len: data_len,
+            max: MAX_OBJECT_DATA_LEN,
+        }));
+    }
+
+    let (first, rest) = rle.split_at(rle.len().min(MAX_PAYLOAD_LEN - FIRST_FRAGMENT_HEADER_LEN));
+    let mut rest = rest
+        .chunks(MAX_PAYLOAD_LEN - NEXT_FRAGMENT_HEADER_LEN)
+        .peekable();
+
+    let first = ObjectDefinition {
+        id: object.id,
+        version: object.version,
+        sequence_flag: match rest.peek() {
+            Some(_) => SequenceFlag::First,
+            None => SequenceFlag::Both,
+        },
+        width: object.width,
+        height: object.height,
+        data_len: Some(data_len as u32),
+        data: first.to_vec(),
+    };
+
+    let rest = std::iter::from_fn(move || {
+        let data = rest.next()?;
+
+        Some(ObjectDefinition {
+            id: object.id,
+            version: object.version,
+            sequence_flag: match rest.peek() {
+                Some(_) => SequenceFlag::Middle,
+                None => SequenceFlag::Last,
+            },
+            width: 0,
+            height: 0,
+            data_len: None,
+            data: data.to_vec(),
+        })
+    });
+
+    Ok(std::iter::once(first).chain(rest))
+}
+
+fn timestamp_field(timestamp: Timestamp) -> [u8; 4] {
+    (timestamp.ticks() as u32).to_be_bytes()
+}
+
+fn encode_payload(data: &SegmentData, buf: &mut Vec<u8>) -> Result<(), Error> {
+    match data {
+        SegmentData::Pcs(pcs) => encode_pcs(pcs, buf),
+        SegmentData::Wds(windows) => encode_wds(windows, buf),
+        SegmentData::Pds(pds) => {
+            encode_pds(pds, buf);
+            Ok(())
+        }
+        SegmentData::Ods(ods) => encode_ods(ods, buf),
+        SegmentData::End => Ok(()),
+    }
+}
+
+/// Converts a count to the single byte that precedes a list of entries.
+fn count_field(field: &'static str, len: usize) -> Result<u8, Error> {
+    u8::try_from(len).map_err(|_| {
+        Error::new(ErrorKind::TooLong {
+            field,
+            len,
+            max: usize::from(u8::MAX),
+        })
+    })
+}
+
+fn encode_pcs(pcs: &PresentationComposition, buf: &mut Vec<u8>) -> Result<(), Error> {
+    let object_count = count_field("PCS composition objects", pcs.composition_objects.len())?;
+
+    buf.extend_from_slice(&pcs.width.to_be_bytes());
+    buf.extend_from_slice(&pcs.height.to_be_bytes());
+    buf.push(pcs.frame_rate);
+    buf.extend_from_slice(&pcs.comp_no.to_be_bytes());
+    buf.push(match pcs.comp_state {
+        CompositionState::EpochStart => 0x80,
+        CompositionState::AcquisitionPoint => 0x40,
+        CompositionState::Normal => 0x00,
+    });
+    buf.push(if pcs.palette_update { 0x80 } else { 0x00 });
+    buf.push(pcs.palette_id);
+    buf.push(object_count);
+
+    for obj in &pcs.composition_objects {
+        encode_composition_object(obj, buf);
+    }
+
+    Ok(())
+}
+
+fn encode_composition_object(obj: &CompositionObject, buf: &mut Vec<u8>) {
+    let mut flags = 0;
+
+    if obj.cropped {
+        flags |= OBJECT_FLAG_CROPPED;
+    }
+
+    if obj.forced {
+        flags |= OBJECT_FLAG_FORCED;
+    }
+
+    buf.extend_from_slice(&obj.id.to_be_bytes());
+    buf.push(obj.window_id);
+    buf.push(flags);
+    buf.extend_from_slice(&obj.x.to_be_bytes());
+    buf.extend_from_slice(&obj.y.to_be_bytes());
+
+    // cropped objects read without a crop rectangle are written back without one
+    if obj.cropped
+        && let (Some(crop_x), Some(crop_y), Some(crop_width), Some(crop_height)) =
+            (obj.crop_x, obj.crop_y, obj.crop_width, obj.crop_height)
+    {
+        for field in [crop_x, crop_y, crop_width, crop_height] {
+            buf.extend_from_slice(&field.to_be_bytes());
+        }
+    }
+}
+
+fn encode_wds(windows: &[WindowDefinition], buf: &mut Vec<u8>) -> Result<(), Error> {
+    buf.push(count_field("WDS windows", windows.len())?);
+
+    for window in windows {
+        buf.push(window.id);
+
+        for field in [window.x, window.y, window.width, window.height] {
+            buf.extend_from_slice(&field.to_be_bytes());
+        }
+    }
+
+    Ok(())
+}
+
+fn encode_pds(pds: &PaletteDefinition, buf: &mut Vec<u8>) {
+    buf.push(pds.id);
+    buf.push(pds.version);
+
+    for entry in &pds.entries {
+        buf.extend_from_slice(&[entry.id, entry.y, entry.cr, entry.cb, entry.alpha]);
+    }
+}
+
+/// Encodes an ODS fragment. A first fragment without a data length is given the length of its
+/// own data, which is only right for complete objects.
+fn encode_ods(ods: &ObjectDefinition, buf: &mut Vec<u8>) -> Result<(), Error> {
+    buf.extend_from_slice(&ods.id.to_be_bytes());
+    buf.push(ods.version);
+    buf.push(match ods.sequence_flag {
+        SequenceFlag::Middle => 0x00,
+        SequenceFlag::Last => 0x40,
+        SequenceFlag::First => 0x80,
+        SequenceFlag::Both => 0xC0,
+    });
+
+    if ods.sequence_flag.has_dimensions() {
+        let data_len = ods
+            .data_len
+            .map_or(ods.data.len() + 4, |data_len| data_len as usize);
+
+        if data_len > MAX_OBJECT_DATA_LEN {
+            return Err(Error::new(ErrorKind::TooLong {
+                field: "ODS object data",
+                len: data_len,
+                max: MAX_OBJECT_DATA_LEN,
+            }));
+        }
+
+        buf.extend_from_slice(&(data_len as u32).to_be_bytes()[1..]);
+        buf.extend_from_slice(&ods.width.to_be_bytes());
+        buf.extend_from_slice(&ods.height.to_be_bytes());
+    }
+
+    buf.extend_from_slice(&ods.data);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decode::object::ObjectAssembler, parse_frames, parse_segment};
+
+    fn assert_segments_round_trip(path: &str) {
+        let bytes = std::fs::read(path).unwrap();
+        let mut input = bytes.as_slice();
+        let mut written = Vec::new();
+
+        while !input.is_empty() {
+            let start = bytes.len() - input.len();
+            let segment = parse_segment(&mut input).unwrap();
+            let end = bytes.len() - input.len();
+
+            let mut encoded = Vec::new();
+            write_segment(&mut encoded, &segment).unwrap();
+            assert_eq!(bytes[start..end], encoded, "segment at byte offset {start}");
+
+            written.extend(encoded);
+        }
+
+        assert_eq!(bytes, written);
+    }
+
+    #[test]
+    fn segments_encode_to_the_bytes_they_were_parsed_from() {
+        assert_segments_round_trip("data/small.sup");
+        assert_segments_round_trip("data/mummyforced.sup");
+    }
+
+    #[test]
+    fn display_sets_round_trip() {
+        for path in ["data/small.sup", "data/mummyforced.sup"] {
+            let frames = parse_frames(&std::fs::read(path).unwrap()).unwrap();
+
+            let mut written = Vec::new();
+            write_display_sets(&mut written, &frames).unwrap();
+
+            assert_eq!(frames, parse_frames(&written).unwrap(), "{path}");
+        }
+    }
+
+    #[test]
+    fn splits_large_objects_into_fragments() {
+        // 1002 bytes of RLE data per line, enough to need three segments
+        let tall = Object::from_pixels(1000, 140, &[1; 140_000]);
+
+        let fragments = object_fragments(&tall).unwrap().collect::<Vec<_>>();
+        let flags = fragments
+            .iter()
+            .map(|fragment| fragment.sequence_flag)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                SequenceFlag::First,
+                SequenceFlag::Middle,
+                SequenceFlag::Last
+            ],
+            flags,
+        );
+
+        let mut assembler = ObjectAssembler::default();
+        let mut reassembled = None;
+
+        for fragment in fragments {
+            let segment = Segment {
+                pts: Timestamp::default(),
+                dts: Timestamp::default(),
+                data: SegmentData::Ods(fragment),
+            };
+
+            let mut written = Vec::new();
+            encode_segment(&segment, &mut written).unwrap();
+            assert!(written.len() <= HEADER_LEN + MAX_PAYLOAD_LEN);
+
+            let SegmentData::Ods(ods) = parse_segment(&mut written.as_slice()).unwrap().data else {
+                panic!("expected an ODS");
+            };
+            reassembled = assembler.push(ods).unwrap();
+        }
+
+        assert_eq!(Some(tall), reassembled);
+    }
+
+    #[test]
+    fn rejects_oversized_payload() {
+        let segment = Segment {
+            pts: Timestamp::from_ticks(90),
+            dts: Timestamp::default(),
+            data: SegmentData::Ods(ObjectDefinition {
+                id: 0,
+                version: 0,
+                sequence_flag: SequenceFlag::Middle,
+                width: 0,
+                height: 0,
+                data_len: None,
+                data: vec![0; MAX_PAYLOAD_LEN],
+            }),
+        };
+
+        let mut buf = vec![1, 2, 3];
+        let err = encode_segment(&segment, &mut buf).unwrap_err();
+
+        assert!(matches!(
+            err.kind(),
+            ErrorKind::TooLong {
+                field: "PGS segment payload",
+                len: 65_539,
+                max: MAX_PAYLOAD_LEN,
+            }
+        ));
+        assert_eq!(Some(Timestamp::from_ticks(90)), err.pts());
+        assert_eq!(vec![1, 2, 3], buf);
+    }
+}
@@ -0,0 +1,315 @@
+//! Palette quantisation of true-colour bitmaps, for authoring objects from RGBA images.
+
+use std::collections::HashMap;
+
+use crate::{
+    Error,
+    decode::{
+        object::Object,
+        ods::ObjectDefinition,
+        pds::{PaletteDefinition, PaletteEntry},
+    },
+};
+
+/// Most colours a palette can hold besides the transparent entry 0.
+const MAX_COLORS: usize = 255;
+
+/// An RGBA image reduced to palette indices.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Quantized {
+    /// Width in pixels.
+    pub width: u16,
+    /// Height in pixels.
+    pub height: u16,
+    /// One palette index per pixel, in row-major order.
+    pub pixels: Vec<u8>,
+    /// RGBA colour of each palette index; index 0 is always fully transparent.
+    pub colors: Vec<[u8; 4]>,
+}
+
+impl Quantized {
+    /// Palette holding every colour of the image, converted to YCrCb.
+    pub fn palette(&self, id: u8, version: u8) -> PaletteDefinition {
+        PaletteDefinition {
+            id,
+            version,
+            entries: (0..=u8::MAX)
+                .zip(&self.colors)
+                .map(|(index, &color)| PaletteEntry::from_rgba(index, color))
+                .collect(),
+        }
+    }
+
+    /// Object whose bitmap is the quantised image.
+    pub fn object(&self, id: u16, version: u8) -> Object {
+        Object::encode(id, version, self.width, self.height, &self.pixels)
+    }
+}
+
+/// Reduces an RGBA image, 4 bytes per pixel in row-major order, to at most 255 colours plus
+/// transparency.
+///
+/// Fully transparent pixels become index 0. Images with few enough colours keep them exactly;
+/// others are reduced by median cut, weighted by how many pixels use each colour.
+///
+/// # Panics
+///
+/// Panics if `rgba` is not exactly `4 * width * height` bytes long.
+pub fn quantize_rgba(width: u16, height: u16, rgba: &[u8]) -> Quantized {
+    assert_eq!(
+        4 * usize::from(width) * usize::from(height),
+        rgba.len(),
+        "image must hold 4 bytes per pixel",
+    );
+
+    let (rgba, _) = rgba.as_chunks::<4>();
+
+    let mut counts = HashMap::<[u8; 4], u32>::new();
+    for &color in rgba.iter().filter(|color| color[3] != 0) {
+        *counts.entry(color).or_default() += 1;
+    }
+
+    let mut histogram = counts.into_iter().collect::<Vec<_>>();
+    // sorted so that the palette does not depend on hash order
+    histogram.sort_unstable();
+
+    let boxes = median_cut(&mut histogram);
+
+    let mut colors = vec![[0; 4]];
+    let mut indices = HashMap::with_capacity(histogram.len());
+
+    for (index, colors_in_box) in
+        (1..=u8::MAX).zip(boxes.iter().map(|range| &histogram[range.clone()]))
+    {
+        colors.push(mean_color(colors_in_box));
+        indices.extend(colors_in_box.iter().map(|&(color, _)| (color, index)));
+    }
+
+    let pixels = rgba
+        .iter()
+        .map(|color| match color[3] {
+            0 => 0,
+            _ => indices[color],
+        })
+        .collect();
+
+    Quantized {
+        width,
+        height,
+        pixels,
+        colors,
+    }
+}
+
+/// Quantises an RGBA image and splits it into the palette and ODS fragments that define it.
+///
+/// The palette and object both start at version 0.
+pub fn encode_rgba(
+    object_id: u16,
+    palette_id: u8,
+    width: u16,
+    height: u16,
+    rgba: &[u8],
+) -> Result<(PaletteDefinition, Vec<ObjectDefinition>), Error> {
+    let quantized = quantize_rgba(width, height, rgba);
+    let object = quantized.object(object_id, 0);
+    let fragments = super::object_fragments(&object)?.collect();
+
+    Ok((quantized.palette(palette_id, 0), fragments))
+}
+
+/// Splits the histogram into at most [`MAX_COLORS`] ranges of similar colours, reordering it so
+/// that every range is contiguous.
+///
+/// The range whose colours spread furthest along one channel is split at the pixel-weighted
+/// median of that channel until there are enough ranges or no range has two colours left.
+fn median_cut(histogram: &mut [([u8; 4], u32)]) -> Vec<std::ops::Range<usize>> {
+    if histogram.is_empty() {
+        return Vec::new();
+    }
+
+    if histogram.len() <= MAX_COLORS {
+        return (0..histogram.len()).map(|index| index..index + 1).collect();
+    }
+
+    let mut boxes = Vec::with_capacity(MAX_COLORS);
+    boxes.push(0..histogram.len());
+
+    while boxes.len() < MAX_COLORS {
+        let widest = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, range)| range.len() > 1)
+            .map(|(index, range)| {
+                let (channel, spread) = widest_channel(&histogram[range.clone()]);
+                (spread, index, channel)
+            })
+            .max();
+
+        let Some((_, index, channel)) = widest else {
+            break;
+        };
+
+        let range = boxes[index].clone();
+        let colors = &mut histogram[range.clone()];
+        colors.sort_unstable_by_key(|&(color, _)| color[channel]);
+
+        let total = colors
+            .iter()
+            .map(|&(_, count)| u64::from(count))
+            .sum::<u64>();
+        let mut seen = 0;
+        let median = colors
+            .iter()
+            .position(|&(_, count)| {
+                seen += u64::from(count);
+                2 * seen >= total
+            })
+            .unwrap_or(0);
+
+        // both halves keep at least one colour
+        let split = range.start + (median + 1).clamp(1, colors.len() - 1);
+
+        boxes[index] = range.start..split;
+        boxes.push(split..range.end);
+    }
+
+    boxes
+}
+
+/// Channel with the largest difference between its smallest and largest value.
+fn widest_channel(colors: &[([u8; 4], u32)]) -> (usize, u8) {
+    (0..4)
+        .map(|channel| {
+            let values = colors.iter().map(|(color, _)| color[channel]);
+            let spread = values.clone().max().unwrap_or(0) - values.min().unwrap_or(0);
+            (channel, spread)
+        })
+        .max_by_key(|&(_, spread)| spread)
+        .unwrap()
+}
+
+/// Pixel-weighted mean of a non-empty set of colours.
+fn mean_color(colors: &[([u8; 4], u32)]) -> [u8; 4] {
+    let total = colors
+        .iter()
+        .map(|&(_, count)| u64::from(count))
+        .sum::<u64>();
+
+    std::array::from_fn(|channel| {
+        let sum = colors
+            .iter()
+            .map(|&(color, count)| u64::from(color[channel]) * u64::from(count))
+            .sum::<u64>();
+
+        ((sum + total / 2) / total) as u8
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::{object::ObjectAssembler, ods::SequenceFlag};
+
+    /// Decodes an object back to RGBA through its palette, like a renderer would.
+    fn render(palette: &PaletteDefinition, object: &Object) -> Vec<u8> {
+        let mut rgba = vec![0; 4 * usize::from(object.width) * usize::from(object.height)];
+        object.decode_rgba_into(&palette.rgba_lut(), &mut rgba);
+        rgba
+    }
+
+    #[test]
+    fn keeps_few_colors_exactly() {
+        let red = [255, 0, 0, 255];
+        let white = [255, 255, 255, 128];
+        let clear = [9, 9, 9, 0];
+        let image = [red, white, clear, red, red, white].concat();
+
+        let quantized = quantize_rgba(3, 2, &image);
+
+        assert_eq!(vec![[0; 4], red, white], quantized.colors);
+        assert_eq!(vec![1, 2, 0, 1, 1, 2], quantized.pixels);
+
+        let palette = quantized.palette(0, 0);
+        assert_eq!(0, palette.find_by_id(0).unwrap().alpha);
+
+        let rendered = render(&palette, &quantized.object(0, 0));
+        for (expected, found) in image.chunks(4).zip(rendered.chunks(4)) {
+            match expected[3] {
+                0 => assert_eq!([0; 4], found),
+                _ => assert!(expected.iter().zip(found).all(|(a, b)| a.abs_diff(*b) <= 2)),
+            }
+        }
+    }
+
+    #[test]
+    fn reduces_many_colors_to_a_full_palette() {
+        // 4096 distinct colours
+        let image = (0..64 * 64)
+            .flat_map(|i: u32| [(i % 64 * 4) as u8, (i / 64 * 4) as u8, 128, 255])
+            .collect::<Vec<_>>();
+
+        let quantized = quantize_rgba(64, 64, &image);
+
+        assert_eq!(256, quantized.colors.len());
+        assert!(quantized.pixels.iter().all(|&index| index != 0));
+
+        // 255 boxes over a 64x64 grid cover about 4x4 grid steps each
+        let errors = quantized
+            .pixels
+            .iter()
+            .zip(image.chunks(4))
+            .flat_map(|(&index, color)| {
+                let quantized = quantized.colors[usize::from(index)];
+                quantized
+                    .into_iter()
+                    .zip(color)
+                    .map(|(a, &b)| a.abs_diff(b))
+            })
+            .collect::<Vec<_>>();
+
+        assert!(errors.iter().all(|&error| error <= 16));
+        assert!(errors.iter().map(|&error| u32::from(error)).sum::<u32>() / 4096 <= 8);
+    }
+
+    #[test]
+    fn fragments_large_bitmaps() {
+        // noise defeats run-length encoding, so this needs several segments
+        let mut seed = 1u32;
+        let image = (0..400 * 300)
+            .flat_map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                [(seed >> 24) as u8 | 1, 0, 0, 255]
+            })
+            .collect::<Vec<_>>();
+
+        let (palette, fragments) = encode_rgba(3, 1, 400, 300, &image).unwrap();
+        assert_eq!(1, palette.id);
+        assert!(palette.entries.len() <= 256);
+
+        let flags = fragments
+            .iter()
+            .map(|fragment| fragment.sequence_flag)
+            .collect::<Vec<_>>();
+        assert_eq!(SequenceFlag::First, flags[0]);
+        assert!(
+            flags[1..flags.len() - 1]
+                .iter()
+                .all(|&flag| flag == SequenceFlag::Middle)
+        );
+        assert_eq!(Some(&SequenceFlag::Last), flags.last());
+
+        let mut assembler = ObjectAssembler::default();
+        let mut object = None;
+        for fragment in fragments {
+            object = assembler.push(fragment).unwrap();
+        }
+        let object = object.unwrap();
+
+        assert_eq!((3, 400, 300), (object.id, object.width, object.height));
+        assert_eq!(
+            quantize_rgba(400, 300, &image).pixels,
+            object.decode_pixels()
+        );
+    }
+}
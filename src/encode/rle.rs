@@ -0,0 +1,146 @@
+//! Run-length encoding of object bitmaps; see `decode/rle.rs` for the code forms.
+
+/// Longest run a single code can describe.
+const MAX_RUN: usize = 0x3FFF;
+
+/// Longest run the short forms can describe.
+const MAX_SHORT_RUN: usize = 0x3F;
+
+/// Encodes a `width` by `height` bitmap of palette indices, in row-major order, into the most
+/// compact PGS RLE stream, with an end-of-line marker after every line.
+///
+/// # Panics
+///
+/// Panics if `pixels` is not exactly `width * height` bytes long.
+pub fn encode_rle(width: u16, height: u16, pixels: &[u8]) -> Vec<u8> {
+    let width = usize::from(width);
+
+    assert_eq!(
+        width * usize::from(height),
+        pixels.len(),
+        "bitmap must hold one byte per pixel",
+    );
+
+    let mut rle = Vec::with_capacity(pixels.len() / 4);
+
+    // a zero-width bitmap still has its lines
+    for line in (0..usize::from(height)).map(|y| &pixels[y * width..(y + 1) * width]) {
+        let mut rest = line;
+
+        while let Some(&color) = rest.first() {
+            let len = rest
+                .iter()
+                .position(|&pixel| pixel != color)
+                .unwrap_or(rest.len());
+            encode_run(color, len, &mut rle);
+            rest = &rest[len..];
+        }
+
+        rle.extend([0, 0]);
+    }
+
+    rle
+}
+
+/// Appends the shortest codes for `len` pixels of `color`.
+///
+/// Codes cost more the longer the run they describe, so splitting long runs into as many
+/// maximum-length codes as possible before the remainder is optimal.
+fn encode_run(color: u8, mut len: usize, rle: &mut Vec<u8>) {
+    while len > 0 {
+        let run = len.min(MAX_RUN);
+        len -= run;
+
+        let [len_hi, len_lo] = (run as u16).to_be_bytes();
+
+        match (color, run) {
+            (0, ..=MAX_SHORT_RUN) => rle.extend([0, len_lo]),
+            (0, _) => rle.extend([0, 0x40 | len_hi, len_lo]),
+            // one or two pixels are shorter as literals than as a run
+            (_, 1) => rle.push(color),
+            (_, 2) => rle.extend([color, color]),
+            (_, ..=MAX_SHORT_RUN) => rle.extend([0, 0x80 | len_lo, color]),
+            (_, _) => rle.extend([0, 0xC0 | len_hi, len_lo, color]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::decode::rle::{RleMode, decode_rle_stream};
+
+    #[test]
+    fn picks_shortest_code_for_each_run() {
+        let cases: [(u8, usize, &[u8]); 10] = [
+            (0, 1, &[0, 1]),
+            (0, 63, &[0, 63]),
+            (0, 64, &[0, 0x40, 64]),
+            (0, 16_383, &[0, 0x7F, 0xFF]),
+            (5, 1, &[5]),
+            (5, 2, &[5, 5]),
+            (5, 3, &[0, 0x83, 5]),
+            (5, 64, &[0, 0xC0, 64, 5]),
+            (5, 16_384, &[0, 0xFF, 0xFF, 5, 5]),
+            (0, 16_384, &[0, 0x7F, 0xFF, 0, 1]),
+        ];
+
+        for (color, len, expected) in cases {
+            let mut rle = Vec::new();
+            encode_run(color, len, &mut rle);
+            assert_eq!(expected, rle, "{len} pixels of color {color}");
+        }
+    }
+
+    #[test]
+    fn ends_every_line() {
+        assert_eq!(
+            vec![0, 0x83, 1, 0, 0, 2, 0, 1, 2, 0, 0],
+            encode_rle(3, 2, &[1, 1, 1, 2, 0, 2]),
+        );
+        assert_eq!(vec![0, 0, 0, 0], encode_rle(0, 2, &[]));
+    }
+
+    #[test]
+    fn reencodes_sample_objects_no_larger() {
+        let bytes = std::fs::read("data/mummyforced.sup").unwrap();
+
+        for frame in crate::parse_frames(&bytes).unwrap() {
+            for object in &frame.objects {
+                let rle = encode_rle(object.width, object.height, &object.decode_pixels());
+                assert!(rle.len() <= object.rle().len());
+            }
+        }
+    }
+
+    /// Bitmaps built from runs, so long runs and runs across line ends come up often.
+    fn bitmaps() -> impl Strategy<Value = (u16, u16, Vec<u8>)> {
+        let color = prop_oneof![Just(0), Just(1), any::<u8>()];
+        let runs = prop::collection::vec((color, 1..20_000usize), 1..16);
+        let dimensions = prop_oneof![(0..=64u16, 0..=64u16), (16_000..=40_000u16, 1..=2u16)];
+
+        (dimensions, runs).prop_map(|((width, height), runs)| {
+            let len = usize::from(width) * usize::from(height);
+            let pixels = runs
+                .into_iter()
+                .flat_map(|(color, len)| std::iter::repeat_n(color, len))
+                .cycle()
+                .take(len)
+                .collect();
+
+            (width, height, pixels)
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn decodes_to_encoded_bitmap((width, height, pixels) in bitmaps()) {
+            let rle = encode_rle(width, height, &pixels);
+            let decoded = decode_rle_stream(&rle, width, height, RleMode::Strict).unwrap();
+
+            prop_assert_eq!(pixels, decoded);
+        }
+    }
+}
@@ -0,0 +1,284 @@
+use std::{fmt, io};
+
+use winnow::error::{ContextError, ErrMode, StrContext, StrContextValue};
+
+use crate::{SegmentType, Timestamp};
+
+/// Label of the check that a segment payload has been fully consumed.
+pub(crate) const END_OF_PAYLOAD: &str = "end of segment payload";
+
+/// What went wrong while decoding a PGS stream.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// A segment did not start with the "PG" magic number.
+    BadMagic,
+
+    /// A segment header had a type byte that is not a known segment type.
+    UnknownSegmentType(u8),
+
+    /// The input ended before the named field was complete.
+    Truncated { field: &'static str },
+
+    /// A segment payload had bytes left over after its last field.
+    TrailingData { len: usize },
+
+    /// The named flag or enumeration field had a value outside its allowed set.
+    InvalidFlag { field: &'static str, value: u8 },
+
+    /// The named length field disagrees with the data it describes.
+    InvalidLength {
+        field: &'static str,
+        expected: usize,
+        found: usize,
+    },
+
+    /// A PDS defined the same palette entry more than once.
+    DuplicatePaletteEntry { id: u8 },
+
+    /// An ODS continuation fragment arrived without a preceding first fragment.
+    OrphanFragment { object_id: u16 },
+
+    /// Run-length encoded data described more pixels than the object has.
+    RleOverrun { expected: usize, decoded: usize },
+
+    /// A line of run-length encoded data was not as wide as its object.
+    RleLineWidth {
+        line: usize,
+        expected: usize,
+        found: usize,
+    },
+
+    /// Run-length encoded data had a different number of lines than its object.
+    RleLineCount { expected: usize, found: usize },
+
+    /// Run-length encoded data ended without closing its last line.
+    RleMissingEol { line: usize },
+
+    /// Run-length encoded data contained a run of zero pixels.
+    RleZeroLengthRun { line: usize },
+
+    /// The named data was too long for the length field that has to describe it.
+    TooLong {
+        field: &'static str,
+        len: usize,
+        max: usize,
+    },
+
+    /// A transport stream packet did not start with the sync byte.
+    TsSyncLost,
+
+    /// Reading from or writing to the underlying stream failed.
+    Io(io::Error),
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadMagic => write!(f, "missing \"PG\" segment marker"),
+            Self::UnknownSegmentType(byte) => write!(f, "unknown segment type {byte:#04x}"),
+            Self::Truncated { field } => write!(f, "truncated {field}"),
+            Self::TrailingData { len } => write!(f, "{len} unexpected bytes after segment payload"),
+            Self::InvalidFlag { field, value } => write!(f, "invalid {field} {value:#04x}"),
+            Self::InvalidLength {
+                field,
+                expected,
+                found,
+            } => write!(f, "{field}: expected {expected} bytes, found {found}"),
+            Self::DuplicatePaletteEntry { id } => write!(f, "palette entry {id} defined twice"),
+            Self::OrphanFragment { object_id } => write!(
+                f,
+                "continuation fragment of object {object_id} without a first fragment",
+            ),
+            Self::RleOverrun { expected, decoded } => write!(
+                f,
+                "RLE data decodes to {decoded} pixels but the object only has {expected}",
+            ),
+            Self::RleLineWidth {
+                line,
+                expected,
+                found,
+            } => write!(
+                f,
+                "RLE line {line} has {found} pixels but the object is {expected} wide",
+            ),
+            Self::RleLineCount { expected, found } => write!(
+                f,
+                "RLE data has {found} lines but the object is {expected} high",
+            ),
+            Self::RleMissingEol { line } => {
+                write!(f, "RLE line {line} is missing its end-of-line marker")
+            }
+            Self::RleZeroLengthRun { line } => write!(f, "zero-length RLE run on line {line}"),
+            Self::TooLong { field, len, max } => {
+                write!(f, "{field} length {len} exceeds the maximum of {max}")
+            }
+            Self::TsSyncLost => write!(f, "lost transport stream sync"),
+            Self::Io(err) => write!(f, "I/O error: {err}"),
+        }
+    }
+}
+
+/// Error returned when a PGS stream cannot be decoded or encoded.
+///
+/// Besides the [`ErrorKind`], errors carry as much location information as was available where
+/// they were raised: errors from [`parse_frames`](crate::parse_frames) and friends know the byte
+/// offset, while a [`Decoder`](crate::Decoder) fed segments directly only knows which segment it
+/// was given.
+#[derive(Debug)]
+pub struct Error {
+    kind: ErrorKind,
+    position: usize,
+    segment_offset: Option<usize>,
+    segment_index: Option<usize>,
+    segment_type: Option<SegmentType>,
+    pts: Option<Timestamp>,
+}
+
+impl Error {
+    pub(crate) fn new(kind: ErrorKind) -> Self {
+        Self {
+            kind,
+            position: 0,
+            segment_offset: None,
+            segment_index: None,
+            segment_type: None,
+            pts: None,
+        }
+    }
+
+    /// Classifies a winnow error raised while `rest` was left unparsed of a field that starts
+    /// `position` bytes into its segment.
+    pub(crate) fn from_parser(err: ErrMode<ContextError>, rest: &[u8], position: usize) -> Self {
+        let err = match err {
+            ErrMode::Backtrack(err) | ErrMode::Cut(err) => err,
+            ErrMode::Incomplete(_) => ContextError::new(),
+        };
+
+        let field = err
+            .context()
+            .find_map(|context| match context {
+                StrContext::Label(label) => Some(*label),
+                _ => None,
+            })
+            .unwrap_or("PGS segment");
+        let expects_value = err.context().any(|context| {
+            matches!(
+                context,
+                StrContext::Expected(StrContextValue::Description(_))
+            )
+        });
+
+        let kind = match (field, rest.first()) {
+            ("PGS magic number", _) => ErrorKind::BadMagic,
+            ("PGS segment type", Some(&byte)) => ErrorKind::UnknownSegmentType(byte),
+            (END_OF_PAYLOAD, _) => ErrorKind::TrailingData { len: rest.len() },
+            (field, Some(&value)) if expects_value => ErrorKind::InvalidFlag { field, value },
+            (field, _) => ErrorKind::Truncated { field },
+        };
+
+        Self {
+            position,
+            ..Self::new(kind)
+        }
+    }
+
+    pub(crate) fn position(&self) -> usize {
+        self.position
+    }
+
+    pub(crate) fn at_position(mut self, position: usize) -> Self {
+        self.position = position;
+        self
+    }
+
+    pub(crate) fn at_offset(mut self, segment_offset: usize) -> Self {
+        self.segment_offset = Some(segment_offset);
+        self
+    }
+
+    pub(crate) fn in_segment(mut self, index: usize) -> Self {
+        self.segment_index.get_or_insert(index);
+        self
+    }
+
+    pub(crate) fn with_segment_type(mut self, segment_type: SegmentType) -> Self {
+        self.segment_type.get_or_insert(segment_type);
+        self
+    }
+
+    pub(crate) fn with_pts(mut self, pts: Timestamp) -> Self {
+        self.pts.get_or_insert(pts);
+        self
+    }
+
+    /// What went wrong, without the location.
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
+
+    /// Absolute byte offset at which decoding failed.
+    pub fn offset(&self) -> Option<usize> {
+        self.segment_offset.map(|offset| offset + self.position)
+    }
+
+    /// Absolute byte offset of the header of the segment that failed to decode.
+    pub fn segment_offset(&self) -> Option<usize> {
+        self.segment_offset
+    }
+
+    /// Zero-based index of the failed segment within the stream.
+    pub fn segment_index(&self) -> Option<usize> {
+        self.segment_index
+    }
+
+    /// Type of the failed segment, if its header could be read.
+    pub fn segment_type(&self) -> Option<SegmentType> {
+        self.segment_type
+    }
+
+    /// Presentation timestamp of the failed segment, if its header could be read.
+    pub fn pts(&self) -> Option<Timestamp> {
+        self.pts
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.kind)?;
+
+        match (self.segment_type, self.segment_index) {
+            (Some(segment_type), Some(index)) => {
+                write!(f, " in {segment_type:?} segment #{index}")?
+            }
+            (Some(segment_type), None) => write!(f, " in {segment_type:?} segment")?,
+            (None, Some(index)) => write!(f, " in segment #{index}")?,
+            (None, None) => {}
+        }
+
+        if let Some(pts) = self.pts {
+            write!(f, " (pts {pts})")?;
+        }
+
+        if let Some(offset) = self.offset() {
+            write!(f, " at byte offset {offset}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.kind {
+            ErrorKind::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Self::new(ErrorKind::Io(err))
+    }
+}
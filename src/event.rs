@@ -0,0 +1,182 @@
+//! Subtitle events: what is on screen, from when, until when.
+
+use std::time::Duration;
+
+use crate::{DisplaySet, DisplayUpdate, Error, Timestamp, decode::parse_display_sets};
+
+/// A subtitle shown on screen for a span of time.
+///
+/// Events start when a display set shows something and end when a later display set either
+/// clears the screen or replaces what was shown. Palette-only updates of the same objects, like
+/// fades, do not end an event.
+#[derive(Debug, Clone)]
+pub struct SubtitleEvent {
+    /// Presentation time at which the subtitle appears.
+    pub start: Timestamp,
+    /// Presentation time at which the subtitle disappears, or `None` if the stream ended while it
+    /// was still shown.
+    pub end: Option<Timestamp>,
+    /// Display set that started the event. Palette-only updates made while it was shown are not
+    /// included.
+    pub display_set: DisplaySet,
+}
+
+impl SubtitleEvent {
+    /// How long the subtitle is shown for, if it ends.
+    pub fn duration(&self) -> Option<Duration> {
+        self.end?.checked_duration_since(self.start)
+    }
+
+    /// Whether the subtitle is flagged for forced display.
+    pub fn is_forced(&self) -> bool {
+        self.display_set.is_forced()
+    }
+}
+
+/// Pairs display sets into [`SubtitleEvent`]s.
+///
+/// Feed every display set in stream order, including those that clear the screen, then call
+/// [`finish`](Self::finish) to flush a subtitle that is still shown at the end of the stream.
+#[derive(Debug, Clone, Default)]
+pub struct EventBuilder {
+    shown: Option<DisplaySet>,
+}
+
+impl EventBuilder {
+    /// Constructs a builder with nothing on screen.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Presents the next display set, returning the event it ends, if any.
+    pub fn push(&mut self, display_set: DisplaySet) -> Option<SubtitleEvent> {
+        if let Some(shown) = &self.shown
+            && display_set.update == DisplayUpdate::PaletteOnly
+            && !display_set.is_empty()
+            && shows_same_objects(shown, &display_set)
+        {
+            return None;
+        }
+
+        let end = display_set.pts;
+        let next = (!display_set.is_empty()).then_some(display_set);
+
+        std::mem::replace(&mut self.shown, next).map(|shown| SubtitleEvent {
+            start: shown.pts,
+            end: Some(end),
+            display_set: shown,
+        })
+    }
+
+    /// Ends the stream, returning an open-ended event if a subtitle is still shown.
+    pub fn finish(self) -> Option<SubtitleEvent> {
+        self.shown.map(|shown| SubtitleEvent {
+            start: shown.pts,
+            end: None,
+            display_set: shown,
+        })
+    }
+}
+
+/// Whether two display sets place the same objects in the same way, whatever their palettes.
+fn shows_same_objects(a: &DisplaySet, b: &DisplaySet) -> bool {
+    a.pcs.composition_objects == b.pcs.composition_objects && a.objects == b.objects
+}
+
+/// Parses a whole PGS stream into subtitle events with start and end times.
+pub fn parse_events(bytes: &[u8]) -> Result<Vec<SubtitleEvent>, Error> {
+    let mut builder = EventBuilder::new();
+    let mut events = Vec::new();
+
+    for display_set in parse_display_sets(bytes)? {
+        events.extend(builder.push(display_set));
+    }
+
+    events.extend(builder.finish());
+
+    Ok(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(millis: u64) -> Timestamp {
+        Timestamp::from_millis(millis)
+    }
+
+    #[test]
+    fn clearing_composition_ends_event() {
+        let bytes = std::fs::read("data/small.sup").unwrap();
+        let events = parse_events(&bytes).unwrap();
+
+        assert_eq!(1, events.len());
+        assert_eq!(Timestamp::from_ticks(158_701_050), events[0].start);
+        assert_eq!(Some(Timestamp::from_ticks(158_948_820)), events[0].end);
+        assert_eq!(Some(Duration::from_millis(2_753)), events[0].duration());
+        assert!(events[0].is_forced());
+    }
+
+    #[test]
+    fn every_sample_event_is_closed() {
+        let bytes = std::fs::read("data/mummyforced.sup").unwrap();
+        let events = parse_events(&bytes).unwrap();
+
+        assert_eq!(22, events.len());
+        assert!(
+            events
+                .iter()
+                .all(|event| event.end.is_some_and(|end| end > event.start))
+        );
+    }
+
+    #[test]
+    fn palette_only_update_keeps_event_open() {
+        let bytes = std::fs::read("data/small.sup").unwrap();
+        let shown = crate::parse_frames(&bytes).unwrap().remove(0);
+
+        let mut faded = shown.clone();
+        faded.pts = ms(20_000);
+        faded.update = DisplayUpdate::PaletteOnly;
+        faded.pcs.palette_update = true;
+        faded
+            .pds
+            .entries
+            .iter_mut()
+            .for_each(|entry| entry.alpha /= 2);
+
+        let mut cleared = shown.clone();
+        cleared.pts = ms(21_000);
+        cleared.pcs.composition_objects.clear();
+
+        let mut builder = EventBuilder::new();
+        assert!(builder.push(shown.clone()).is_none());
+        assert!(builder.push(faded).is_none());
+
+        let event = builder.push(cleared).unwrap();
+        assert_eq!(shown.pts, event.start);
+        assert_eq!(Some(ms(21_000)), event.end);
+        assert_eq!(shown, event.display_set);
+        assert!(builder.finish().is_none());
+    }
+
+    #[test]
+    fn replacing_composition_ends_event_and_stream_end_leaves_it_open() {
+        let bytes = std::fs::read("data/small.sup").unwrap();
+        let shown = crate::parse_frames(&bytes).unwrap().remove(0);
+
+        let mut replacement = shown.clone();
+        replacement.pts = ms(20_000);
+
+        let mut builder = EventBuilder::new();
+        assert!(builder.push(shown.clone()).is_none());
+
+        let event = builder.push(replacement).unwrap();
+        assert_eq!(shown.pts, event.start);
+        assert_eq!(Some(ms(20_000)), event.end);
+
+        let event = builder.finish().unwrap();
+        assert_eq!(ms(20_000), event.start);
+        assert_eq!(None, event.end);
+    }
+}
@@ -0,0 +1,45 @@
+//! Decoder for Blu-ray Presentation Graphic Stream (PGS) subtitles, commonly stored as `.sup`
+//! files.
+//!
+//! A PGS stream is a sequence of segments (see [`segment`]) which are grouped into display sets
+//! (see [`decode`]). Most users only need [`parse_frames`]:
+//!
+//! ```no_run
+//! let bytes = std::fs::read("subtitles.sup")?;
+//! let frames = sup_decode::parse_frames(&bytes)?;
+//!
+//! for frame in &frames {
+//!     println!("{} {:?}", frame.pts, frame.pcs);
+//! }
+//! # Ok::<_, Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! Tools that only need to scan segments can use the zero-copy [`raw`] module instead, and
+//! [`encode`] writes segments and display sets back out. PGS streams still muxed into Blu-ray
+//! `.m2ts` files can be read with the [`ts`] module, and with the `ocr` feature, `ocr` recognises the
+//! text of decoded subtitles.
+
+pub mod decode;
+pub mod encode;
+mod error;
+pub mod event;
+#[cfg(feature = "ocr")]
+pub mod ocr;
+pub mod raw;
+pub mod segment;
+pub mod stream;
+mod timestamp;
+pub mod ts;
+
+#[cfg(feature = "parallel")]
+pub use self::decode::{decode_bitmaps_parallel, parse_frames_parallel};
+pub use self::{
+    decode::{
+        BitmapCache, Decoder, DisplaySet, DisplayUpdate, Placement, Rect, RleMode, parse_frames,
+        parse_frames_lenient,
+    },
+    error::{Error, ErrorKind},
+    event::{SubtitleEvent, parse_events},
+    segment::{Segment, SegmentData, SegmentType, parse_segment},
+    timestamp::Timestamp,
+};
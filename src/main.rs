@@ -1,30 +1,118 @@
-use std::{env, fs};
+use std::{
+    env,
+    fs::File,
+    io::{self, BufReader, Read},
+    path::Path,
+    sync::Arc,
+};
+
+use sup_decode::{ocr, stream::DisplaySets, ts};
 
-mod decode;
-mod ocr;
-mod segment;
 mod ui;
 
-pub(crate) use decode::DisplaySet;
+const USAGE: &str = "sup-decode [--list-streams] [--pid <pid>] <file.sup|file.m2ts|->";
 
 fn main() -> eyre::Result<()> {
     color_eyre::install()?;
 
-    let mut args = env::args();
-    let file = args.nth(1).unwrap();
-    let bytes = fs::read(file)?;
-    let frames = decode::parse_frames(&bytes).map_err(|err| eyre::eyre!("{err:?}"))?;
-    let mut ocr_engine: Box<dyn ocr::OcrEngine> = match ocr::TesseractOcrEngine::new("eng") {
-        Ok(engine) => Box::new(engine),
-        Err(err) => Box::new(ocr::NoopOcrEngine::new(err.to_string())),
+    let mut list_streams = false;
+    let mut pid = None;
+    let mut file = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--list-streams" => list_streams = true,
+            "--pid" => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| eyre::eyre!("--pid needs a value"))?;
+                pid = Some(parse_pid(&value)?);
+            }
+            flag if flag.starts_with("--") => {
+                eyre::bail!("unknown option {flag}\nusage: {USAGE}");
+            }
+            _ if file.is_some() => eyre::bail!("unexpected argument {arg}\nusage: {USAGE}"),
+            _ => file = Some(arg),
+        }
+    }
+
+    let file = file.ok_or_else(|| eyre::eyre!("usage: {USAGE}"))?;
+
+    if (list_streams || pid.is_some()) && has_extension(&file, &["sup"]) {
+        eyre::bail!("--list-streams and --pid only apply to transport streams, not {file}");
+    }
+
+    // `-` reads the stream from stdin so other tools can pipe into the viewer
+    let mut reader: Box<dyn Read> = if file == "-" {
+        Box::new(io::stdin().lock())
+    } else {
+        Box::new(BufReader::new(File::open(&file)?))
+    };
+
+    // sniff the start of the input so that piped transport streams are recognised too
+    let mut head = Vec::with_capacity(ts::PROBE_LEN);
+    reader
+        .by_ref()
+        .take(ts::PROBE_LEN as u64)
+        .read_to_end(&mut head)?;
+    let is_transport_stream = ts::is_transport_stream(&head);
+    let mut reader = io::Cursor::new(head).chain(reader);
+
+    let frames = if list_streams
+        || pid.is_some()
+        || is_transport_stream
+        || has_extension(&file, &["m2ts", "mts", "ts"])
+    {
+        // transport streams are demuxed in one go, so that the program tables can be found first
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+
+        let streams = ts::pgs_streams(&bytes)?;
+
+        if list_streams {
+            for stream in &streams {
+                let language = stream.language.as_deref().unwrap_or("und");
+                println!("{:#06x} {language}", stream.pid);
+            }
+
+            return Ok(());
+        }
+
+        let pid = match pid {
+            Some(pid) => pid,
+            None => {
+                streams
+                    .first()
+                    .ok_or_else(|| eyre::eyre!("no PGS streams found in {file}"))?
+                    .pid
+            }
+        };
+
+        ts::parse_frames(&bytes, pid)?
+    } else {
+        let mut frames = Vec::new();
+
+        for display_set in DisplaySets::new(reader) {
+            let display_set = display_set?;
+
+            if !display_set.is_empty() {
+                frames.push(display_set);
+            }
+        }
+
+        frames
     };
-    let ocr_frames = ocr::recognize_frames(&mut *ocr_engine, &frames);
+
     let num_frames = frames.len();
 
+    // the app constructor may run more than once; share the decoded frames instead of copying them
+    let frames = Arc::<[_]>::from(frames);
+
     println!("processed {num_frames} frames");
 
     iced::application(
-        move || ui::SupViewer::new(frames.clone(), ocr_frames.clone()),
+        move || ui::SupViewer::new(Arc::clone(&frames), ocr::default_engine()),
         ui::SupViewer::update,
         ui::SupViewer::view,
     )
@@ -33,3 +121,20 @@ fn main() -> eyre::Result<()> {
 
     Ok(())
 }
+
+/// Whether the file name has one of `extensions`, ignoring case.
+fn has_extension(file: &str, extensions: &[&str]) -> bool {
+    Path::new(file)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| extensions.contains(&ext.to_ascii_lowercase().as_str()))
+}
+
+/// Parses a PID given in decimal or, with a `0x` prefix, in hexadecimal.
+fn parse_pid(value: &str) -> eyre::Result<u16> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
+    .map_err(|err| eyre::eyre!("invalid PID {value:?}: {err}"))
+}
@@ -1,68 +1,132 @@
+//! Optical character recognition of decoded subtitle bitmaps.
+//!
+//! Only available with the `ocr` feature. The [`TesseractOcrEngine`] shells out to the
+//! `tesseract` command, so it has to be installed separately.
+
 use std::{
-    env, fs,
+    env, error, fmt, fs, io,
     path::PathBuf,
     process::Command,
     time::{SystemTime, UNIX_EPOCH},
 };
 
-use chrono::NaiveTime;
-use eyre::{Context as _, Result, bail};
-
-use crate::DisplaySet;
+use crate::{BitmapCache, DisplaySet, Rect, Timestamp};
 
 const TRANSPARENT: [f32; 4] = [0.0, 0.0, 0.0, 0.0];
 
+/// Error returned when an OCR backend cannot be run or fails.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum OcrError {
+    /// The backend could not be started.
+    Spawn(io::Error),
+
+    /// The subtitle bitmap could not be written out for the backend.
+    Image(image::ImageError),
+
+    /// The backend ran but reported a failure.
+    Backend(String),
+}
+
+impl fmt::Display for OcrError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Spawn(err) => write!(f, "failed to run OCR backend: {err}"),
+            Self::Image(err) => write!(f, "failed to write OCR input image: {err}"),
+            Self::Backend(msg) => write!(f, "OCR backend failed: {msg}"),
+        }
+    }
+}
+
+impl error::Error for OcrError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Self::Spawn(err) => Some(err),
+            Self::Image(err) => Some(err),
+            Self::Backend(_) => None,
+        }
+    }
+}
+
+/// RGBA8 rendering of a display set's subtitle bitmap.
 #[derive(Debug, Clone)]
-pub(crate) struct SubtitleRaster {
-    pub(crate) width: u32,
-    pub(crate) height: u32,
-    pub(crate) pixels: Vec<u8>,
+pub struct SubtitleRaster {
+    /// Width in pixels.
+    pub width: u32,
+    /// Height in pixels.
+    pub height: u32,
+    /// Row-major RGBA8 pixels.
+    pub pixels: Vec<u8>,
 }
 
+/// A single recognised word.
 #[derive(Debug, Clone)]
-pub(crate) struct OcrWord {
-    pub(crate) text: String,
-    pub(crate) confidence: Option<f32>,
+pub struct OcrWord {
+    /// Text of the word.
+    pub text: String,
+    /// Backend confidence from 0 to 100, if it reported one.
+    pub confidence: Option<f32>,
 }
 
+/// Text recognised in a subtitle bitmap.
 #[derive(Debug, Clone, Default)]
-pub(crate) struct OcrData {
-    pub(crate) text: String,
-    pub(crate) mean_confidence: Option<f32>,
-    pub(crate) words: Vec<OcrWord>,
+pub struct OcrData {
+    /// Recognised text, one line per subtitle line.
+    pub text: String,
+    /// Mean confidence of the words that have one.
+    pub mean_confidence: Option<f32>,
+    /// Recognised words in reading order.
+    pub words: Vec<OcrWord>,
 }
 
+/// Outcome of running OCR on a display set.
 #[derive(Debug, Clone)]
-pub(crate) enum OcrState {
+pub enum OcrState {
+    /// The engine cannot run, for the given reason.
     NotConfigured(String),
+    /// Text was recognised.
     Recognized(OcrData),
+    /// Recognition failed, with the given message.
     Failed(String),
 }
 
+/// OCR result for one display set.
 #[derive(Debug, Clone)]
-pub(crate) struct OcrFrame {
-    pub(crate) pts: NaiveTime,
-    pub(crate) backend: &'static str,
-    pub(crate) subtitle_size: (u32, u32),
-    pub(crate) state: OcrState,
+pub struct OcrFrame {
+    /// Presentation time of the recognised display set.
+    pub pts: Timestamp,
+    /// Whether the recognised display set is flagged for forced display.
+    pub forced: bool,
+    /// Name of the engine that produced the result.
+    pub backend: &'static str,
+    /// Width and height of the visible subtitle area.
+    pub subtitle_size: (u32, u32),
+    /// What the engine made of the subtitle.
+    pub state: OcrState,
 }
 
-pub(crate) trait OcrEngine {
+/// Text recognition backend.
+pub trait OcrEngine: fmt::Debug {
+    /// Short name of the backend.
     fn name(&self) -> &'static str;
 
+    /// Whether the backend can run at all.
     fn is_configured(&self) -> bool {
         true
     }
 
+    /// Why the backend cannot run, if it is not configured.
     fn not_configured_reason(&self) -> Option<&str> {
         None
     }
 
-    fn recognize(&mut self, raster: &SubtitleRaster) -> Result<OcrData>;
+    /// Recognises the text in a rendered subtitle.
+    fn recognize(&mut self, raster: &SubtitleRaster) -> Result<OcrData, OcrError>;
 }
 
+/// Engine that recognises nothing, standing in for a backend that is not available.
 #[derive(Debug)]
-pub(crate) struct NoopOcrEngine {
+pub struct NoopOcrEngine {
     reason: String,
 }
 
@@ -75,7 +139,8 @@ impl Default for NoopOcrEngine {
 }
 
 impl NoopOcrEngine {
-    pub(crate) fn new(reason: impl Into<String>) -> Self {
+    /// Constructs an engine that reports `reason` for not being configured.
+    pub fn new(reason: impl Into<String>) -> Self {
         Self {
             reason: reason.into(),
         }
@@ -95,28 +160,33 @@ impl OcrEngine for NoopOcrEngine {
         Some(&self.reason)
     }
 
-    fn recognize(&mut self, _raster: &SubtitleRaster) -> Result<OcrData> {
+    fn recognize(&mut self, _raster: &SubtitleRaster) -> Result<OcrData, OcrError> {
         Ok(OcrData::default())
     }
 }
 
+/// Engine that runs the `tesseract` command on each subtitle.
 #[derive(Debug, Clone)]
-pub(crate) struct TesseractOcrEngine {
+pub struct TesseractOcrEngine {
     binary: PathBuf,
     language: String,
     page_segmentation_mode: u8,
 }
 
 impl TesseractOcrEngine {
-    pub(crate) fn new(language: impl Into<String>) -> Result<Self> {
+    /// Checks that `tesseract` can be run and constructs an engine for the given language code.
+    pub fn new(language: impl Into<String>) -> Result<Self, OcrError> {
         let binary = PathBuf::from("tesseract");
         let version = Command::new(&binary)
             .arg("--version")
             .output()
-            .with_context(|| "failed to execute `tesseract --version`")?;
+            .map_err(OcrError::Spawn)?;
 
         if !version.status.success() {
-            bail!("`tesseract --version` exited with {}", version.status);
+            return Err(OcrError::Backend(format!(
+                "`tesseract --version` exited with {}",
+                version.status
+            )));
         }
 
         Ok(Self {
@@ -132,7 +202,7 @@ impl OcrEngine for TesseractOcrEngine {
         "tesseract"
     }
 
-    fn recognize(&mut self, raster: &SubtitleRaster) -> Result<OcrData> {
+    fn recognize(&mut self, raster: &SubtitleRaster) -> Result<OcrData, OcrError> {
         let image_path = temp_ocr_path("png");
         let pixels = raster_to_luma(raster);
 
@@ -143,7 +213,7 @@ impl OcrEngine for TesseractOcrEngine {
             raster.height,
             image::ColorType::L8,
         )
-        .with_context(|| format!("write OCR input image to {}", image_path.display()))?;
+        .map_err(OcrError::Image)?;
 
         let output = Command::new(&self.binary)
             .arg(&image_path)
@@ -154,80 +224,119 @@ impl OcrEngine for TesseractOcrEngine {
             .arg(self.page_segmentation_mode.to_string())
             .arg("tsv")
             .arg("quiet")
-            .output()
-            .with_context(|| "run tesseract OCR")?;
+            .output();
 
         let _ = fs::remove_file(&image_path);
+        let output = output.map_err(OcrError::Spawn)?;
 
         if !output.status.success() {
             let stderr = String::from_utf8_lossy(&output.stderr);
-            bail!("tesseract failed: {}", stderr.trim());
+            return Err(OcrError::Backend(stderr.trim().to_owned()));
+        }
+
+        let tsv = String::from_utf8(output.stdout)
+            .map_err(|_| OcrError::Backend("TSV output is not valid UTF-8".to_owned()))?;
+        Ok(parse_tesseract_tsv(&tsv))
+    }
+}
+
+/// Tesseract with English text if it is installed, or an engine explaining why it is not.
+pub fn default_engine() -> Box<dyn OcrEngine> {
+    match TesseractOcrEngine::new("eng") {
+        Ok(engine) => Box::new(engine),
+        Err(err) => Box::new(NoopOcrEngine::new(err.to_string())),
+    }
+}
+
+/// Runs `engine` over one display set, decoding its bitmaps through `bitmaps`.
+///
+/// Nothing is decoded if the engine is not configured.
+pub fn recognize_frame(
+    engine: &mut dyn OcrEngine,
+    frame: &DisplaySet,
+    bitmaps: &BitmapCache,
+) -> OcrFrame {
+    let subtitle_size = subtitle_bounds(frame)
+        .map(|bounds| (u32::from(bounds.width), u32::from(bounds.height)))
+        .unwrap_or((0, 0));
+
+    let state = if !engine.is_configured() {
+        OcrState::NotConfigured(
+            engine
+                .not_configured_reason()
+                .unwrap_or("OCR backend unavailable")
+                .to_owned(),
+        )
+    } else if let Some(raster) = rasterize_subtitle(frame, bitmaps) {
+        match engine.recognize(&raster) {
+            Ok(data) => OcrState::Recognized(data),
+            Err(err) => OcrState::Failed(err.to_string()),
         }
+    } else {
+        OcrState::Failed("no subtitle objects placed in frame".to_owned())
+    };
 
-        let tsv = String::from_utf8(output.stdout).context("decode tesseract TSV output")?;
-        parse_tesseract_tsv(&tsv)
+    OcrFrame {
+        pts: frame.pts,
+        forced: frame.is_forced(),
+        backend: engine.name(),
+        subtitle_size,
+        state,
     }
 }
 
-pub(crate) fn recognize_frames(engine: &mut dyn OcrEngine, frames: &[DisplaySet]) -> Vec<OcrFrame> {
+/// Runs `engine` over every display set in order.
+///
+/// Bitmaps are decoded one display set at a time through a bounded [`BitmapCache`], and not at
+/// all if the engine is not configured.
+pub fn recognize_frames(engine: &mut dyn OcrEngine, frames: &[DisplaySet]) -> Vec<OcrFrame> {
+    let bitmaps = BitmapCache::default();
+
     frames
         .iter()
-        .map(|frame| {
-            let raster = rasterize_subtitle(frame);
-            let subtitle_size = raster
-                .as_ref()
-                .map(|raster| (raster.width, raster.height))
-                .unwrap_or((0, 0));
-
-            let state = if !engine.is_configured() {
-                OcrState::NotConfigured(
-                    engine
-                        .not_configured_reason()
-                        .unwrap_or("OCR backend unavailable")
-                        .to_owned(),
-                )
-            } else if let Some(raster) = raster {
-                match engine.recognize(&raster) {
-                    Ok(data) => OcrState::Recognized(data),
-                    Err(err) => OcrState::Failed(err.to_string()),
-                }
-            } else {
-                OcrState::Failed("subtitle object missing from frame".to_owned())
-            };
-
-            OcrFrame {
-                pts: frame.pts,
-                backend: engine.name(),
-                subtitle_size,
-                state,
-            }
-        })
+        .map(|frame| recognize_frame(engine, frame, &bitmaps))
         .collect()
 }
 
-fn rasterize_subtitle(frame: &DisplaySet) -> Option<SubtitleRaster> {
-    let ods = &frame.ods;
-    let _obj = frame.pcs.find_object_by_id(ods.id)?;
-    let width = u32::from(ods.width);
-    let height = u32::from(ods.height);
+/// Bounding box of the visible parts of every placement in `frame`.
+fn subtitle_bounds(frame: &DisplaySet) -> Option<Rect> {
+    frame
+        .placements()
+        .filter_map(|placement| placement.visible_rect())
+        .reduce(|bounds, rect| bounds.union(&rect))
+}
+
+/// Renders every object placed by `frame` to RGBA, cropped to the bounding box of the visible
+/// parts of all placements, or `None` if nothing is visible.
+///
+/// Object bitmaps are taken from `bitmaps`, which decodes them if needed.
+pub fn rasterize_subtitle(frame: &DisplaySet, bitmaps: &BitmapCache) -> Option<SubtitleRaster> {
+    let bounds = subtitle_bounds(frame)?;
+
+    let width = u32::from(bounds.width);
+    let height = u32::from(bounds.height);
     let mut pixels = vec![0_u8; width as usize * height as usize * 4];
 
-    for (index, color_id) in ods.data.iter().copied().enumerate() {
-        let rgba = if color_id == 0 {
-            TRANSPARENT
-        } else {
-            frame
+    for placement in frame.placements() {
+        for (x, y, color_id) in placement.pixels(bitmaps) {
+            if color_id == 0 {
+                continue;
+            }
+
+            let rgba = frame
                 .pds
                 .find_by_id(color_id)
                 .map(|entry| entry.rgba())
-                .unwrap_or(TRANSPARENT)
-        };
-
-        let offset = index * 4;
-        pixels[offset] = (rgba[0] * 255.0).round() as u8;
-        pixels[offset + 1] = (rgba[1] * 255.0).round() as u8;
-        pixels[offset + 2] = (rgba[2] * 255.0).round() as u8;
-        pixels[offset + 3] = (rgba[3] * 255.0).round() as u8;
+                .unwrap_or(TRANSPARENT);
+
+            let x = usize::from(x - bounds.x);
+            let y = usize::from(y - bounds.y);
+            let offset = (y * width as usize + x) * 4;
+            pixels[offset] = (rgba[0] * 255.0).round() as u8;
+            pixels[offset + 1] = (rgba[1] * 255.0).round() as u8;
+            pixels[offset + 2] = (rgba[2] * 255.0).round() as u8;
+            pixels[offset + 3] = (rgba[3] * 255.0).round() as u8;
+        }
     }
 
     Some(SubtitleRaster {
@@ -254,7 +363,7 @@ fn raster_to_luma(raster: &SubtitleRaster) -> Vec<u8> {
         .collect()
 }
 
-fn parse_tesseract_tsv(tsv: &str) -> Result<OcrData> {
+fn parse_tesseract_tsv(tsv: &str) -> OcrData {
     let mut words = Vec::new();
     let mut lines = Vec::<(String, Vec<String>)>::new();
 
@@ -304,11 +413,11 @@ fn parse_tesseract_tsv(tsv: &str) -> Result<OcrData> {
         Some(confidences.iter().sum::<f32>() / confidences.len() as f32)
     };
 
-    Ok(OcrData {
+    OcrData {
         text,
         mean_confidence,
         words,
-    })
+    }
 }
 
 fn temp_ocr_path(extension: &str) -> PathBuf {
@@ -340,7 +449,7 @@ level\tpage_num\tblock_num\tpar_num\tline_num\tword_num\tleft\ttop\twidth\theigh
 5\t1\t1\t1\t2\t2\t35\t30\t35\t10\t80.0\tlove
 ";
 
-        let data = parse_tesseract_tsv(tsv).unwrap();
+        let data = parse_tesseract_tsv(tsv);
 
         assert_eq!("French, the language\nof love", data.text);
         assert_eq!(5, data.words.len());
@@ -352,6 +461,19 @@ level\tpage_num\tblock_num\tpar_num\tline_num\tword_num\tleft\ttop\twidth\theigh
         assert_eq!(Some(95.5), data.words[0].confidence);
     }
 
+    #[test]
+    fn unconfigured_engine_decodes_nothing() {
+        let bytes = fs::read("data/small.sup").unwrap();
+        let frame = crate::parse_frames(&bytes).unwrap().remove(0);
+        let bitmaps = BitmapCache::default();
+
+        let ocr = recognize_frame(&mut NoopOcrEngine::default(), &frame, &bitmaps);
+
+        assert!(matches!(ocr.state, OcrState::NotConfigured(_)));
+        assert_ne!((0, 0), ocr.subtitle_size);
+        assert_eq!(0, bitmaps.len());
+    }
+
     #[test]
     fn ignores_non_word_rows_and_negative_confidence() {
         let tsv = "\
@@ -361,7 +483,7 @@ level\tpage_num\tblock_num\tpar_num\tline_num\tword_num\tleft\ttop\twidth\theigh
 5\t1\t1\t1\t1\t2\t45\t10\t30\t10\t90\tworld
 ";
 
-        let data = parse_tesseract_tsv(tsv).unwrap();
+        let data = parse_tesseract_tsv(tsv);
 
         assert_eq!("Hello world", data.text);
         assert_eq!(2, data.words.len());
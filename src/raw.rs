@@ -0,0 +1,252 @@
+//! Zero-copy view of a PGS stream for tools that scan segments without decoding them.
+//!
+//! A [`Segment`] here only parses the 13-byte header and borrows its payload from the input, so
+//! walking a whole stream with [`segments`] allocates nothing. Payloads are decoded on request
+//! with [`Segment::decode`], and object bitmaps only when
+//! [`ObjectFragment::decode_pixels`](crate::decode::ods::ObjectFragment::decode_pixels) is called.
+//!
+//! ```no_run
+//! use sup_decode::SegmentType;
+//!
+//! let bytes = std::fs::read("subtitles.sup")?;
+//! let mut rle_bytes = 0;
+//!
+//! for segment in sup_decode::raw::segments(&bytes) {
+//!     let segment = segment?;
+//!
+//!     if segment.segment_type == SegmentType::ODS {
+//!         rle_bytes += segment.object_fragment().unwrap()?.data.len();
+//!     }
+//! }
+//! # Ok::<_, Box<dyn std::error::Error>>(())
+//! ```
+
+use winnow::{
+    Bytes,
+    binary::{be_u16, be_u32},
+    error::StrContext,
+    prelude::*,
+    token::literal,
+};
+
+use crate::{
+    Error, ErrorKind, SegmentType, Timestamp,
+    decode::ods::{ObjectFragment, decode_ods},
+    segment::{self, HEADER_LEN, MAGIC, parse_payload, parse_segment_type},
+};
+
+/// A PGS segment whose payload is borrowed from the input and not yet decoded.
+#[derive(Debug, Clone, Copy)]
+pub struct Segment<'a> {
+    /// Presentation timestamp from the segment header.
+    pub pts: Timestamp,
+    /// Decoding timestamp from the segment header.
+    pub dts: Timestamp,
+    /// Type byte from the segment header.
+    pub segment_type: SegmentType,
+    /// Undecoded payload.
+    pub payload: &'a [u8],
+}
+
+impl<'a> Segment<'a> {
+    /// Parses one segment header from the front of `input` and borrows its payload, advancing
+    /// `input` past the segment.
+    ///
+    /// On error, `input` is left untouched. Error offsets are relative to the start of `input`.
+    pub fn parse(input: &mut &'a [u8]) -> Result<Self, Error> {
+        decode_raw_segment(input).map_err(|err| err.at_offset(0))
+    }
+
+    /// Number of bytes this segment occupies in the stream, header included.
+    pub fn encoded_len(&self) -> usize {
+        HEADER_LEN + self.payload.len()
+    }
+
+    /// Decodes the payload into an owned [`Segment`](crate::Segment).
+    ///
+    /// Error offsets are relative to the start of this segment.
+    pub fn decode(&self) -> Result<crate::Segment, Error> {
+        let data = segment::decode_payload(self.segment_type, self.payload)
+            .map_err(|err| self.locate(err))?;
+
+        Ok(crate::Segment {
+            pts: self.pts,
+            dts: self.dts,
+            data,
+        })
+    }
+
+    /// Parses the payload of an ODS without copying its RLE data, or returns `None` for other
+    /// segment types.
+    pub fn object_fragment(&self) -> Option<Result<ObjectFragment<'a>, Error>> {
+        (self.segment_type == SegmentType::ODS)
+            .then(|| parse_payload(self.payload, decode_ods).map_err(|err| self.locate(err)))
+    }
+
+    fn locate(&self, err: Error) -> Error {
+        err.with_segment_type(self.segment_type).with_pts(self.pts)
+    }
+}
+
+/// Like [`Segment::parse`], but leaves it to the caller to locate errors in the stream.
+pub(crate) fn decode_raw_segment<'a>(input: &mut &'a [u8]) -> Result<Segment<'a>, Error> {
+    let bytes = *input;
+    let mut header = Bytes::new(bytes);
+
+    let (pts, dts, segment_type, seg_len) = (
+        literal(&MAGIC)
+            .void()
+            .context(StrContext::Label("PGS magic number")),
+        be_u32
+            .map(|ticks| Timestamp::from_ticks(u64::from(ticks)))
+            .context(StrContext::Label("PGS presentation timestamp")),
+        be_u32
+            .map(|ticks| Timestamp::from_ticks(u64::from(ticks)))
+            .context(StrContext::Label("PGS decoding timestamp")),
+        parse_segment_type,
+        be_u16.context(StrContext::Label("PGS segment size")),
+    )
+        .map(|(_, pts, dts, seg_type, seg_len)| (pts, dts, seg_type, usize::from(seg_len)))
+        .parse_next(&mut header)
+        .map_err(|err| Error::from_parser(err, header, bytes.len() - header.len()))?;
+
+    let payload = bytes[HEADER_LEN..].get(..seg_len).ok_or_else(|| {
+        Error::new(ErrorKind::Truncated {
+            field: "PGS segment payload",
+        })
+        .at_position(bytes.len())
+        .with_segment_type(segment_type)
+        .with_pts(pts)
+    })?;
+
+    *input = &bytes[HEADER_LEN + seg_len..];
+
+    Ok(Segment {
+        pts,
+        dts,
+        segment_type,
+        payload,
+    })
+}
+
+/// Iterates over the segments of an in-memory PGS stream without decoding them.
+pub fn segments(bytes: &[u8]) -> Segments<'_> {
+    Segments {
+        bytes,
+        rest: bytes,
+        index: 0,
+        failed: false,
+    }
+}
+
+/// Iterator over borrowed segments, created by [`segments`].
+///
+/// Iteration stops after the first error.
+#[derive(Debug, Clone)]
+pub struct Segments<'a> {
+    bytes: &'a [u8],
+    rest: &'a [u8],
+    index: usize,
+    failed: bool,
+}
+
+impl Segments<'_> {
+    /// Byte offset of the next segment to be read.
+    pub fn offset(&self) -> usize {
+        self.bytes.len() - self.rest.len()
+    }
+}
+
+impl<'a> Iterator for Segments<'a> {
+    type Item = Result<Segment<'a>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.rest.is_empty() {
+            return None;
+        }
+
+        let offset = self.offset();
+
+        let next = decode_raw_segment(&mut self.rest)
+            .map_err(|err| err.at_offset(offset).in_segment(self.index));
+        self.failed = next.is_err();
+        self.index += 1;
+
+        Some(next)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{RleMode, decode::ods::SequenceFlag, parse_frames};
+
+    #[test]
+    fn walks_stream_without_decoding() {
+        let bytes = std::fs::read("data/small.sup").unwrap();
+
+        let mut segments = segments(&bytes);
+        let mut offsets = Vec::new();
+
+        while let Some(segment) = segments.next() {
+            let segment = segment.unwrap();
+            offsets.push((
+                segments.offset() - segment.encoded_len(),
+                segment.segment_type,
+            ));
+        }
+
+        assert_eq!(
+            vec![
+                (0, SegmentType::PCS),
+                (32, SegmentType::WDS),
+                (55, SegmentType::PDS),
+                (665, SegmentType::ODS),
+                (18_355, SegmentType::END),
+                (18_368, SegmentType::PCS),
+                (18_392, SegmentType::WDS),
+                (18_415, SegmentType::END),
+            ],
+            offsets,
+        );
+    }
+
+    #[test]
+    fn borrowed_fragment_decodes_like_decoder() {
+        let bytes = std::fs::read("data/small.sup").unwrap();
+
+        let segment = segments(&bytes).nth(3).unwrap().unwrap();
+        let fragment = segment.object_fragment().unwrap().unwrap();
+
+        assert_eq!(SequenceFlag::Both, fragment.sequence_flag);
+        assert!(std::ptr::eq(
+            &bytes[665 + HEADER_LEN + 11],
+            &fragment.data[0]
+        ));
+
+        let pixels = fragment.decode_pixels(RleMode::Strict).unwrap().unwrap();
+        let frames = parse_frames(&bytes).unwrap();
+        assert_eq!(frames[0].objects[0].decode_pixels(), pixels);
+
+        assert!(
+            segments(&bytes)
+                .next()
+                .unwrap()
+                .unwrap()
+                .object_fragment()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn stops_after_error() {
+        let bytes = std::fs::read("data/small.sup").unwrap();
+
+        let mut segments = segments(&bytes[..40]);
+
+        assert!(segments.next().unwrap().is_ok());
+        let err = segments.next().unwrap().unwrap_err();
+        assert_eq!(Some(32), err.segment_offset());
+        assert!(segments.next().is_none());
+    }
+}
@@ -1,14 +1,14 @@
-use chrono::NaiveTime;
+//! Decoded segment model and payload dispatch; see [`raw`] for header parsing.
+
 use winnow::{
     Bytes, ModalResult,
-    binary::{be_u8, be_u16, be_u32, length_take},
+    binary::be_u8,
     combinator::eof,
     error::{StrContext, StrContextValue},
     prelude::*,
-    token::{literal, take},
 };
 
-use crate::decode;
+use crate::{Error, Timestamp, decode, error::END_OF_PAYLOAD, raw};
 
 #[cfg(test)]
 pub(crate) fn segment_on<'a>(bytes: &'a [u8], segmark: &'_ [u8]) -> Vec<&'a [u8]> {
@@ -38,45 +38,88 @@ pub(crate) fn segment_on<'a>(bytes: &'a [u8], segmark: &'_ [u8]) -> Vec<&'a [u8]
     segments
 }
 
-fn convert_ts(ts: u32) -> NaiveTime {
-    let millis = ts / 90;
-    let seconds = millis / 1000;
-    let nanos = (millis % 1000) * 1_000_000;
-
-    NaiveTime::from_num_seconds_from_midnight_opt(seconds, nanos).unwrap()
-}
-
-#[derive(Debug, Clone, Copy)]
-#[expect(clippy::upper_case_acronyms)]
-pub(crate) enum SegmentType {
+/// Type byte found in every segment header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentType {
+    /// Presentation Composition Segment.
     PCS,
+    /// Window Definition Segment.
     WDS,
+    /// Palette Definition Segment.
     PDS,
+    /// Object Definition Segment.
     ODS,
+    /// End of Display Set Segment.
     END,
 }
 
-#[derive(Debug, Clone)]
-pub(crate) enum Segment {
-    Pcs(NaiveTime, decode::pcs::PresentationComposition),
+/// A single decoded PGS segment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    /// Presentation timestamp from the segment header.
+    pub pts: Timestamp,
+    /// Decoding timestamp from the segment header.
+    pub dts: Timestamp,
+    /// Decoded segment payload.
+    pub data: SegmentData,
+}
+
+impl Segment {
+    /// Type of this segment, as stored in its header.
+    pub fn segment_type(&self) -> SegmentType {
+        match self.data {
+            SegmentData::Pcs(_) => SegmentType::PCS,
+            SegmentData::Wds(_) => SegmentType::WDS,
+            SegmentData::Pds(_) => SegmentType::PDS,
+            SegmentData::Ods(_) => SegmentType::ODS,
+            SegmentData::End => SegmentType::END,
+        }
+    }
+}
+
+/// Decoded payload of a PGS segment.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SegmentData {
+    /// Presentation composition.
+    Pcs(decode::pcs::PresentationComposition),
+    /// Window definitions.
     Wds(Vec<decode::wds::WindowDefinition>),
+    /// Palette definition.
     Pds(decode::pds::PaletteDefinition),
+    /// Object definition.
     Ods(decode::ods::ObjectDefinition),
+    /// End of display set marker.
     End,
 }
 
+impl SegmentType {
+    /// Maps a segment header type byte to its segment type.
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        Some(match byte {
+            0x14 => SegmentType::PDS,
+            0x15 => SegmentType::ODS,
+            0x16 => SegmentType::PCS,
+            0x17 => SegmentType::WDS,
+            0x80 => SegmentType::END,
+            _ => return None,
+        })
+    }
+
+    /// Segment header type byte for this segment type.
+    pub fn to_byte(self) -> u8 {
+        match self {
+            SegmentType::PDS => 0x14,
+            SegmentType::ODS => 0x15,
+            SegmentType::PCS => 0x16,
+            SegmentType::WDS => 0x17,
+            SegmentType::END => 0x80,
+        }
+    }
+}
+
 pub(crate) fn parse_segment_type(input: &mut &Bytes) -> ModalResult<SegmentType> {
     be_u8
-        .verify_map(|byte| {
-            Some(match byte {
-                0x14 => SegmentType::PDS,
-                0x15 => SegmentType::ODS,
-                0x16 => SegmentType::PCS,
-                0x17 => SegmentType::WDS,
-                0x80 => SegmentType::END,
-                _ => return None,
-            })
-        })
+        .verify_map(SegmentType::from_byte)
         .context(StrContext::Label("PGS segment type"))
         .context(StrContext::Expected(StrContextValue::Description(
             "0x14, 0x15, 0x16, 0x17, or 0x80",
@@ -84,69 +127,89 @@ pub(crate) fn parse_segment_type(input: &mut &Bytes) -> ModalResult<SegmentType>
         .parse_next(input)
 }
 
-fn parse_payload<T>(
-    seg_data: &[u8],
-    mut parser: fn(&mut &Bytes) -> ModalResult<T>,
-) -> ModalResult<T> {
+pub(crate) fn parse_payload<'a, T>(
+    seg_data: &'a [u8],
+    mut parser: fn(&mut &'a Bytes) -> ModalResult<T>,
+) -> Result<T, Error> {
     let mut input = Bytes::new(seg_data);
-    let parsed = parser.parse_next(&mut input)?;
-    eof.context(StrContext::Expected(StrContextValue::Description(
-        "end of segment payload",
-    )))
-    .parse_next(&mut input)?;
 
-    Ok(parsed)
+    parser
+        .parse_next(&mut input)
+        .and_then(|parsed| {
+            eof.context(StrContext::Label(END_OF_PAYLOAD))
+                .parse_next(&mut input)?;
+            Ok(parsed)
+        })
+        .map_err(|err| Error::from_parser(err, input, HEADER_LEN + seg_data.len() - input.len()))
+}
+
+pub(crate) const MAGIC: [u8; 2] = [0x50, 0x47];
+
+/// Length of the fixed segment header: magic, PTS, DTS, type and payload size.
+pub(crate) const HEADER_LEN: usize = 13;
+
+/// Reads the segment type from a header at the start of `bytes`, if the magic number and type
+/// byte are intact.
+pub(crate) fn peek_segment_type(bytes: &[u8]) -> Option<SegmentType> {
+    if !bytes.starts_with(&MAGIC) {
+        return None;
+    }
+
+    SegmentType::from_byte(*bytes.get(10)?)
+}
+
+/// Finds the offset of the next plausible segment header after `from`.
+///
+/// A header is plausible if it has the "PG" magic number and a known segment type, and its
+/// payload ends either at the end of `bytes` or right before another "PG" marker.
+pub(crate) fn find_next_segment(bytes: &[u8], from: usize) -> Option<usize> {
+    let start = from.checked_add(1)?;
+
+    (start..bytes.len().saturating_sub(HEADER_LEN - 1)).find(|&offset| {
+        let header = &bytes[offset..offset + HEADER_LEN];
+
+        if peek_segment_type(header).is_none() {
+            return false;
+        }
+
+        let payload_len = usize::from(u16::from_be_bytes([header[11], header[12]]));
+        let next = &bytes[offset + HEADER_LEN..];
+
+        next.len() == payload_len
+            || next
+                .get(payload_len..)
+                .is_some_and(|next| next.starts_with(&MAGIC))
+    })
+}
+
+/// Parses one segment from the front of `input`, advancing it past the parsed bytes.
+///
+/// On error, `input` is left untouched. Error offsets are relative to the start of `input`.
+pub fn parse_segment(input: &mut &[u8]) -> Result<Segment, Error> {
+    decode_segment(input).map_err(|err| err.at_offset(0))
 }
 
-pub(crate) fn parse_segment(input: &mut &Bytes) -> ModalResult<Segment> {
-    let (pts, seg_type, seg_data) = (
-        literal(&[0x50, 0x47])
-            .void()
-            .context(StrContext::Label("PGS magic number"))
-            .context(StrContext::Expected(StrContextValue::Description(
-                "\"PG\" segment marker",
-            ))),
-        be_u32
-            .map(convert_ts)
-            .context(StrContext::Label("PGS presentation timestamp")),
-        take(4_usize)
-            .void()
-            .context(StrContext::Label("PGS decoding timestamp")),
-        parse_segment_type,
-        length_take(be_u16.context(StrContext::Label("PGS segment size")))
-            .context(StrContext::Label("PGS segment payload")),
-    )
-        .context(StrContext::Label("PGS segment"))
-        .map(|(_, pts, _, seg_type, seg_data)| (pts, seg_type, seg_data))
-        .parse_next(input)?;
+/// Like [`parse_segment`], but leaves it to the caller to locate errors in the stream.
+pub(crate) fn decode_segment(input: &mut &[u8]) -> Result<Segment, Error> {
+    let mut rest = *input;
+    let segment = raw::decode_raw_segment(&mut rest)?.decode()?;
+
+    *input = rest;
 
+    Ok(segment)
+}
+
+/// Decodes the payload of a segment of type `seg_type`.
+pub(crate) fn decode_payload(seg_type: SegmentType, seg_data: &[u8]) -> Result<SegmentData, Error> {
     match seg_type {
-        SegmentType::PCS => parse_payload(seg_data, decode::pcs::decode_pcs)
-            .map(|seg| Segment::Pcs(pts, seg))
-            .map_err(|err| {
-                err.map(|mut inner| {
-                    inner.push(StrContext::Label("PCS segment"));
-                    inner
-                })
-            }),
-        SegmentType::WDS => parse_payload(seg_data, decode::wds::decode_wds)
-            .map(Segment::Wds)
-            .map_err(|err| {
-                err.map(|mut inner| {
-                    inner.push(StrContext::Label("WDS segment"));
-                    inner
-                })
-            }),
-        SegmentType::PDS => Ok(Segment::Pds(decode::pds::decode_pds(seg_data))),
+        SegmentType::PCS => parse_payload(seg_data, decode::pcs::decode_pcs).map(SegmentData::Pcs),
+        SegmentType::WDS => parse_payload(seg_data, decode::wds::decode_wds).map(SegmentData::Wds),
+        SegmentType::PDS => decode::pds::validate_pds(seg_data)
+            .and_then(|()| parse_payload(seg_data, decode::pds::decode_pds))
+            .map(SegmentData::Pds),
         SegmentType::ODS => parse_payload(seg_data, decode::ods::decode_ods)
-            .map(Segment::Ods)
-            .map_err(|err| {
-                err.map(|mut inner| {
-                    inner.push(StrContext::Label("ODS segment"));
-                    inner
-                })
-            }),
-        SegmentType::END => Ok(Segment::End),
+            .map(|fragment| SegmentData::Ods(fragment.into())),
+        SegmentType::END => parse_payload(seg_data, |_| Ok(())).map(|()| SegmentData::End),
     }
 }
 
@@ -160,6 +223,92 @@ pub(crate) fn parse_segment(input: &mut &Bytes) -> ModalResult<Segment> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::ErrorKind;
+
+    #[test]
+    fn parse_segment_advances_input() {
+        let data = std::fs::read("data/test/pcs.dat").unwrap();
+        let mut input = data.as_slice();
+
+        let segment = parse_segment(&mut input).unwrap();
+
+        assert!(matches!(segment.data, SegmentData::Pcs(_)));
+        assert_eq!(SegmentType::PCS, segment.segment_type());
+        assert_eq!(Timestamp::from_ticks(0x0588_fdec), segment.pts);
+        assert_eq!(Timestamp::from_ticks(0), segment.dts);
+        assert_eq!(&data[32..], input);
+    }
+
+    #[test]
+    fn parse_segment_leaves_input_on_error() {
+        let data = [0x50, 0x48, 0, 0, 0, 0, 0, 0, 0, 0, 0x80, 0, 0];
+        let mut input = data.as_slice();
+
+        parse_segment(&mut input).unwrap_err();
+
+        assert_eq!(data.len(), input.len());
+    }
+
+    #[test]
+    fn classifies_header_errors() {
+        let mut header = [0x50, 0x47, 0, 0, 0, 1, 0, 0, 0, 0, 0x80, 0, 0];
+
+        header[1] = 0x48;
+        let err = parse_segment(&mut header.as_slice()).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::BadMagic));
+        assert_eq!(Some(0), err.offset());
+
+        header[1] = 0x47;
+        header[10] = 0x42;
+        let err = parse_segment(&mut header.as_slice()).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::UnknownSegmentType(0x42)));
+        assert_eq!(Some(10), err.offset());
+
+        header[10] = 0x80;
+        header[12] = 2;
+        let err = parse_segment(&mut header.as_slice()).unwrap_err();
+        assert!(matches!(
+            err.kind(),
+            ErrorKind::Truncated {
+                field: "PGS segment payload"
+            },
+        ));
+        assert_eq!(Some(SegmentType::END), err.segment_type());
+        assert_eq!(Some(Timestamp::from_ticks(1)), err.pts());
+    }
+
+    #[test]
+    fn rejects_end_segment_payload() {
+        let data = [0x50, 0x47, 0, 0, 0, 0, 0, 0, 0, 0, 0x80, 0, 1, 0];
+        let err = parse_segment(&mut data.as_slice()).unwrap_err();
+
+        assert!(matches!(err.kind(), ErrorKind::TrailingData { len: 1 }));
+        assert_eq!(Some(HEADER_LEN), err.offset());
+    }
+
+    #[test]
+    fn locates_payload_errors() {
+        let data = std::fs::read("data/test/pcs.dat").unwrap();
+
+        // truncate the PCS payload in the middle of its composition object
+        let mut truncated = data[..32].to_vec();
+        truncated[12] -= 4;
+        let err = parse_segment(&mut &truncated[..28]).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::Truncated { .. }));
+        assert_eq!(Some(SegmentType::PCS), err.segment_type());
+
+        let mut trailing = data[..32].to_vec();
+        trailing[12] += 2;
+        trailing.extend([0, 0]);
+        let err = parse_segment(&mut trailing.as_slice()).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::TrailingData { len: 2 }));
+        assert_eq!(Some(32), err.offset());
+        assert_eq!(
+            "2 unexpected bytes after segment payload in PCS segment (pts 00:17:11.822) at byte \
+             offset 32",
+            err.to_string(),
+        );
+    }
 
     #[test]
     fn single_marker_segment() {
@@ -0,0 +1,263 @@
+//! Incremental decoding from any [`Read`] source.
+//!
+//! Unlike [`parse_frames`](crate::parse_frames), which needs the whole stream in memory, these
+//! readers pull one segment at a time and yield each display set as soon as its END segment has
+//! been read. Memory use is bounded by the definitions of the current epoch.
+
+use std::io::{self, Read};
+
+use crate::{
+    Decoder, DisplaySet, Error,
+    segment::{HEADER_LEN, Segment, decode_segment},
+};
+
+/// Reads PGS segments one at a time from a byte stream.
+///
+/// As an iterator, it stops after the first error, since the rest of the stream can no longer be
+/// located.
+#[derive(Debug)]
+pub struct SegmentReader<R> {
+    reader: R,
+    offset: usize,
+    index: usize,
+    buf: Vec<u8>,
+    failed: bool,
+}
+
+impl<R: Read> SegmentReader<R> {
+    /// Wraps a byte stream. Wrap unbuffered sources like files in a [`BufReader`] first.
+    ///
+    /// [`BufReader`]: std::io::BufReader
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            offset: 0,
+            index: 0,
+            buf: Vec::new(),
+            failed: false,
+        }
+    }
+
+    /// Byte offset of the next segment to be read.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Converts this reader into an iterator over display sets.
+    pub fn display_sets(self) -> DisplaySets<R> {
+        DisplaySets {
+            segments: self,
+            decoder: Decoder::new(),
+            failed: false,
+        }
+    }
+
+    /// Reads the next segment, or returns `None` at a clean end of stream.
+    pub fn read_segment(&mut self) -> Result<Option<Segment>, Error> {
+        let offset = self.offset;
+        let index = self.index;
+
+        self.buf.resize(HEADER_LEN, 0);
+        let mut read = read_full(&mut self.reader, &mut self.buf)
+            .map_err(|err| Error::from(err).at_offset(offset).in_segment(index))?;
+
+        if read == 0 {
+            return Ok(None);
+        }
+
+        if read == HEADER_LEN {
+            let payload_len = usize::from(u16::from_be_bytes([self.buf[11], self.buf[12]]));
+            self.buf.resize(HEADER_LEN + payload_len, 0);
+            read += read_full(&mut self.reader, &mut self.buf[HEADER_LEN..])
+                .map_err(|err| Error::from(err).at_offset(offset).in_segment(index))?;
+        }
+
+        // a short read leaves a truncated buffer, which the segment parser reports precisely
+        self.buf.truncate(read);
+        let segment = decode_segment(&mut self.buf.as_slice())
+            .map_err(|err| err.at_offset(offset).in_segment(index))?;
+
+        self.offset += self.buf.len();
+        self.index += 1;
+
+        Ok(Some(segment))
+    }
+
+    /// Zero-based index of the next segment to be read.
+    fn index(&self) -> usize {
+        self.index
+    }
+}
+
+impl<R: Read> Iterator for SegmentReader<R> {
+    type Item = Result<Segment, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+
+        let next = self.read_segment().transpose();
+        self.failed = matches!(next, Some(Err(_)));
+        next
+    }
+}
+
+/// Iterator over the display sets of a byte stream, created by
+/// [`SegmentReader::display_sets`].
+///
+/// Every display set is yielded, including those that clear the screen, so the output can be fed
+/// straight into an [`EventBuilder`](crate::event::EventBuilder). Iteration stops after the first
+/// error.
+#[derive(Debug)]
+pub struct DisplaySets<R> {
+    segments: SegmentReader<R>,
+    decoder: Decoder,
+    failed: bool,
+}
+
+impl<R: Read> DisplaySets<R> {
+    /// Wraps a byte stream; see [`SegmentReader::new`].
+    pub fn new(reader: R) -> Self {
+        SegmentReader::new(reader).display_sets()
+    }
+
+    fn next_display_set(&mut self) -> Result<Option<DisplaySet>, Error> {
+        loop {
+            let offset = self.segments.offset();
+            let index = self.segments.index();
+
+            let Some(segment) = self.segments.read_segment()? else {
+                return Ok(None);
+            };
+
+            if let Some(display_set) = self
+                .decoder
+                .push(segment)
+                .map_err(|err| err.at_offset(offset).in_segment(index))?
+            {
+                return Ok(Some(display_set));
+            }
+        }
+    }
+}
+
+impl<R: Read> Iterator for DisplaySets<R> {
+    type Item = Result<DisplaySet, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+
+        let next = self.next_display_set().transpose();
+        self.failed = matches!(next, Some(Err(_)));
+        next
+    }
+}
+
+/// Reads until `buf` is full or the stream ends, returning the number of bytes read.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ErrorKind, parse_frames};
+
+    /// Yields at most `chunk` bytes per read, like a pipe.
+    struct Trickle<'a> {
+        bytes: &'a [u8],
+        chunk: usize,
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.chunk).min(self.bytes.len());
+            buf[..n].copy_from_slice(&self.bytes[..n]);
+            self.bytes = &self.bytes[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn streams_same_display_sets_as_slice_parser() {
+        let bytes = std::fs::read("data/mummyforced.sup").unwrap();
+        let expected = parse_frames(&bytes).unwrap();
+
+        let streamed = DisplaySets::new(Trickle {
+            bytes: &bytes,
+            chunk: 7,
+        })
+        .filter(|ds| !ds.as_ref().is_ok_and(DisplaySet::is_empty))
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+
+        assert_eq!(expected.len(), streamed.len());
+        for (expected, streamed) in expected.iter().zip(&streamed) {
+            assert_eq!(expected.pts, streamed.pts);
+            assert_eq!(expected.objects[0].rle(), streamed.objects[0].rle());
+        }
+    }
+
+    #[test]
+    fn yields_display_set_as_soon_as_it_ends() {
+        let bytes = std::fs::read("data/small.sup").unwrap();
+        let mut display_sets = SegmentReader::new(bytes.as_slice()).display_sets();
+        display_sets.next().unwrap().unwrap();
+
+        // the first display set is five segments long and nothing past its END has been read
+        assert_eq!(18_368, display_sets.segments.offset());
+
+        let mut display_sets = SegmentReader::new(bytes.as_slice()).display_sets();
+        assert!(!display_sets.next().unwrap().unwrap().is_empty());
+        assert!(display_sets.next().unwrap().unwrap().is_empty());
+        assert!(display_sets.next().is_none());
+    }
+
+    #[test]
+    fn reports_truncated_stream() {
+        let bytes = std::fs::read("data/small.sup").unwrap();
+        let mut segments = SegmentReader::new(&bytes[..40]);
+
+        assert!(segments.next().unwrap().is_ok());
+        let err = segments.next().unwrap().unwrap_err();
+        assert_eq!(Some(32), err.segment_offset());
+        assert_eq!(Some(1), err.segment_index());
+        // the first six bytes of the header are intact, the decoding timestamp is cut short
+        assert_eq!(Some(38), err.offset());
+        assert!(matches!(
+            err.kind(),
+            ErrorKind::Truncated {
+                field: "PGS decoding timestamp"
+            },
+        ));
+    }
+
+    #[test]
+    fn segments_stop_after_first_error() {
+        let mut bytes = std::fs::read("data/small.sup").unwrap();
+        // break the magic number of the second segment, which leaves the reader misaligned
+        bytes[33] = 0;
+
+        let mut segments = SegmentReader::new(bytes.as_slice());
+
+        assert!(segments.next().unwrap().is_ok());
+        let err = segments.next().unwrap().unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::BadMagic));
+        assert!(segments.next().is_none());
+        assert!(segments.next().is_none());
+    }
+}
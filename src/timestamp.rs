@@ -0,0 +1,146 @@
+//! Presentation and decoding timestamps.
+
+use std::{fmt, time::Duration};
+
+/// A point in time on the 90 kHz MPEG system clock.
+///
+/// Timestamps keep the raw tick count from the stream, so they can be written back out or shifted
+/// without accumulating rounding errors. Conversions to coarser units round to the nearest unit
+/// but never alter the stored ticks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp(u64);
+
+impl Timestamp {
+    /// Number of clock ticks per second.
+    pub const TICKS_PER_SECOND: u64 = 90_000;
+
+    /// Number of clock ticks per millisecond.
+    pub const TICKS_PER_MILLI: u64 = Self::TICKS_PER_SECOND / 1_000;
+
+    /// Constructs a timestamp from a raw 90 kHz tick count.
+    pub const fn from_ticks(ticks: u64) -> Self {
+        Self(ticks)
+    }
+
+    /// Constructs a timestamp from a whole number of milliseconds. This is exact, except that
+    /// timestamps beyond the largest tick count saturate to it.
+    pub const fn from_millis(millis: u64) -> Self {
+        Self(millis.saturating_mul(Self::TICKS_PER_MILLI))
+    }
+
+    /// Constructs a timestamp from a duration since the clock origin, rounded to the nearest tick.
+    /// Durations beyond the largest tick count saturate to it.
+    pub fn from_duration(duration: Duration) -> Self {
+        let ticks = (duration.as_nanos() * u128::from(Self::TICKS_PER_SECOND) + 500_000_000)
+            / 1_000_000_000;
+        Self(u64::try_from(ticks).unwrap_or(u64::MAX))
+    }
+
+    /// Raw 90 kHz tick count.
+    pub const fn ticks(self) -> u64 {
+        self.0
+    }
+
+    /// Milliseconds since the clock origin, rounded to the nearest millisecond.
+    pub const fn as_millis(self) -> u64 {
+        let rounding = self.0 % Self::TICKS_PER_MILLI >= Self::TICKS_PER_MILLI / 2;
+        self.0 / Self::TICKS_PER_MILLI + rounding as u64
+    }
+
+    /// Time since the clock origin, rounded to the nearest nanosecond.
+    pub fn as_duration(self) -> Duration {
+        let secs = self.0 / Self::TICKS_PER_SECOND;
+        let ticks = self.0 % Self::TICKS_PER_SECOND;
+        // less than a second of ticks never rounds up to a whole second
+        let nanos = (ticks * 1_000_000_000 + Self::TICKS_PER_SECOND / 2) / Self::TICKS_PER_SECOND;
+        Duration::new(secs, nanos as u32)
+    }
+
+    /// Time elapsed since `earlier`, or `None` if `earlier` is later than `self`.
+    pub fn checked_duration_since(self, earlier: Timestamp) -> Option<Duration> {
+        self.0
+            .checked_sub(earlier.0)
+            .map(|ticks| Timestamp(ticks).as_duration())
+    }
+
+    /// Shifts the timestamp by a signed number of ticks, or returns `None` if the result would be
+    /// negative or overflow.
+    pub const fn checked_add_ticks(self, ticks: i64) -> Option<Timestamp> {
+        match self.0.checked_add_signed(ticks) {
+            Some(ticks) => Some(Timestamp(ticks)),
+            None => None,
+        }
+    }
+}
+
+/// Formats as an `HH:MM:SS.mmm` timecode, rounded to the nearest millisecond.
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let millis = self.as_millis();
+
+        write!(
+            f,
+            "{:02}:{:02}:{:02}.{:03}",
+            millis / 3_600_000,
+            millis / 60_000 % 60,
+            millis / 1_000 % 60,
+            millis % 1_000,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_sub_millisecond_ticks() {
+        let ts = Timestamp::from_ticks(158_701_050);
+
+        assert_eq!(158_701_050, ts.ticks());
+        assert_eq!(1_763_345, ts.as_millis());
+        assert_eq!(Duration::new(1_763, 345_000_000), ts.as_duration());
+        assert_eq!("00:29:23.345", ts.to_string());
+
+        let ts = Timestamp::from_ticks(158_701_089);
+        assert_eq!(1_763_345, ts.as_millis());
+        assert_eq!(Duration::new(1_763, 345_433_333), ts.as_duration());
+    }
+
+    #[test]
+    fn duration_round_trips_to_ticks() {
+        for ticks in [0, 1, 89, 90, 45_000, 158_701_089, (1 << 33) - 1] {
+            let ts = Timestamp::from_ticks(ticks);
+            assert_eq!(ts, Timestamp::from_duration(ts.as_duration()));
+        }
+
+        assert_eq!(Timestamp::from_ticks(90_000), Timestamp::from_millis(1_000));
+    }
+
+    #[test]
+    fn conversions_do_not_overflow() {
+        let max = Timestamp::from_ticks(u64::MAX);
+
+        assert_eq!(max, Timestamp::from_millis(u64::MAX));
+        assert_eq!(max, Timestamp::from_duration(Duration::MAX));
+        assert_eq!(u64::MAX / 90, max.as_millis());
+        assert_eq!(
+            Duration::new(u64::MAX / 90_000, 240_166_667),
+            max.as_duration()
+        );
+    }
+
+    #[test]
+    fn durations_between_timestamps() {
+        let start = Timestamp::from_ticks(158_701_050);
+        let end = Timestamp::from_ticks(158_948_820);
+
+        assert_eq!(
+            Some(Duration::from_nanos(2_753_000_000)),
+            end.checked_duration_since(start)
+        );
+        assert_eq!(None, start.checked_duration_since(end));
+        assert_eq!(Some(end), start.checked_add_ticks(247_770));
+        assert_eq!(None, start.checked_add_ticks(-158_701_051));
+    }
+}
@@ -0,0 +1,946 @@
+//! Demultiplexing of PGS streams from MPEG-2 transport streams, as found in Blu-ray `.m2ts` files.
+//!
+//! Both 192-byte BDAV packets (a 4-byte arrival timestamp followed by a transport packet) and
+//! plain 188-byte transport packets are accepted. PGS streams are found through the program
+//! tables, and their PES packets are reassembled into segments that keep the full 33-bit
+//! timestamps.
+//!
+//! Errors in a PES packet are located by the offset of the transport packet it starts in, plus
+//! the position of the failure within the reassembled PES packet.
+//!
+//! ```no_run
+//! let bytes = std::fs::read("00001.m2ts")?;
+//!
+//! for stream in sup_decode::ts::pgs_streams(&bytes)? {
+//!     println!("{:#06x} {:?}", stream.pid, stream.language);
+//! }
+//!
+//! let frames = sup_decode::ts::parse_frames(&bytes, 0x1200)?;
+//! # Ok::<_, Box<dyn std::error::Error>>(())
+//! ```
+
+use std::{collections::HashMap, ops::RangeInclusive};
+
+use crate::{
+    Decoder, DisplaySet, Error, ErrorKind, Segment, SegmentType, Timestamp,
+    segment::{HEADER_LEN, decode_payload},
+};
+
+const SYNC_BYTE: u8 = 0x47;
+
+/// Length of a plain transport packet.
+const TS_PACKET_LEN: usize = 188;
+
+/// Length of a BDAV packet: a 4-byte extra header before a transport packet.
+const BDAV_PACKET_LEN: usize = 192;
+
+const PAT_PID: u16 = 0x0000;
+
+/// Stream type of Presentation Graphics streams in a PMT.
+const PGS_STREAM_TYPE: u8 = 0x90;
+
+/// Descriptor holding the ISO 639 language code of an elementary stream.
+const LANGUAGE_DESCRIPTOR_TAG: u8 = 0x0A;
+
+/// PIDs that Blu-ray discs assign to PGS streams, used when a stream has no program tables.
+pub const PGS_PIDS: RangeInclusive<u16> = 0x1200..=0x121F;
+
+/// Number of bytes from the start of a file that [`is_transport_stream`] looks at.
+pub const PROBE_LEN: usize = 4 * BDAV_PACKET_LEN;
+
+/// A PGS stream found in a transport stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PgsStream {
+    /// Packet identifier of the stream, usually in [`PGS_PIDS`].
+    pub pid: u16,
+    /// Program the stream belongs to, if it was found through the program tables.
+    pub program_number: Option<u16>,
+    /// ISO 639 language code from the program tables, like `"eng"`.
+    pub language: Option<String>,
+}
+
+/// Lists the PGS streams of a transport stream, ordered by PID.
+///
+/// Streams are taken from the program tables. A stream without program tables, like a cut
+/// from a larger file, falls back to the PIDs in [`PGS_PIDS`] that start a PES packet.
+pub fn pgs_streams(bytes: &[u8]) -> Result<Vec<PgsStream>, Error> {
+    let mut tables = ProgramTables::default();
+    let mut fallback = Vec::new();
+
+    for packet in packets(bytes)? {
+        let packet = packet?;
+
+        if tables.push(&packet) {
+            break;
+        }
+
+        if PGS_PIDS.contains(&packet.pid) && packet.unit_start && !fallback.contains(&packet.pid) {
+            fallback.push(packet.pid);
+        }
+    }
+
+    let mut streams = if tables.streams.is_empty() {
+        fallback
+            .into_iter()
+            .map(|pid| PgsStream {
+                pid,
+                program_number: None,
+                language: None,
+            })
+            .collect()
+    } else {
+        tables.streams
+    };
+
+    streams.sort_by_key(|stream| stream.pid);
+
+    Ok(streams)
+}
+
+/// Extracts the segments of the PGS stream carried on `pid`.
+///
+/// Segments take the PTS and DTS of the PES packet carrying them; a PES packet without a DTS is
+/// decoded at its PTS. Errors are reported at the offset of the transport packet that starts the
+/// PES packet at fault.
+pub fn segments(bytes: &[u8], pid: u16) -> Result<Vec<Segment>, Error> {
+    let mut segments = Vec::new();
+    demux(bytes, pid, |segment| {
+        segments.push(segment);
+        Ok(())
+    })?;
+    Ok(segments)
+}
+
+/// Parses the PGS stream carried on `pid` into the display sets that show something on screen,
+/// like [`parse_frames`](crate::parse_frames) does for a `.sup` file.
+pub fn parse_frames(bytes: &[u8], pid: u16) -> Result<Vec<DisplaySet>, Error> {
+    let mut decoder = Decoder::new();
+    let mut display_sets = Vec::new();
+
+    demux(bytes, pid, |segment| {
+        display_sets.extend(decoder.push(segment)?);
+        Ok(())
+    })?;
+
+    display_sets.retain(|ds| !ds.is_empty());
+
+    Ok(display_sets)
+}
+
+/// Whether `bytes`, the start of a file, look like a transport stream rather than a `.sup` file,
+/// judging by where sync bytes are found.
+pub fn is_transport_stream(bytes: &[u8]) -> bool {
+    packet_layout(bytes).is_some()
+}
+
+/// Feeds every segment of the stream on `pid` to `sink`, locating any error it returns.
+fn demux(
+    bytes: &[u8],
+    pid: u16,
+    mut sink: impl FnMut(Segment) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let mut pes = PesAssembler::default();
+    let mut index = 0;
+
+    let mut finish = |pes: Option<Pes>| -> Result<(), Error> {
+        let Some(pes) = pes else {
+            return Ok(());
+        };
+
+        pes.segments(|segment| {
+            let result = segment
+                .and_then(&mut sink)
+                .map_err(|err| err.in_segment(index));
+            index += 1;
+            result
+        })
+        .map_err(|err| err.at_offset(pes.offset))
+    };
+
+    for packet in packets(bytes)? {
+        let packet = packet?;
+
+        if packet.pid == pid {
+            finish(pes.push(&packet))?;
+        }
+    }
+
+    finish(pes.finish())
+}
+
+/// A transport packet with its header parsed.
+#[derive(Debug, Clone, Copy)]
+struct Packet<'a> {
+    /// Offset of the transport packet in the stream, after any BDAV header.
+    offset: usize,
+    pid: u16,
+    /// Set if a PES packet or table section starts in this packet.
+    unit_start: bool,
+    continuity_counter: u8,
+    payload: &'a [u8],
+}
+
+/// Works out the packet length from where sync bytes are found, returning it with the offset of
+/// the transport packet within each packet.
+fn packet_layout(bytes: &[u8]) -> Option<(usize, usize)> {
+    [(BDAV_PACKET_LEN, 4), (TS_PACKET_LEN, 0)]
+        .into_iter()
+        .find(|&(packet_len, sync_offset)| {
+            let mut syncs = (sync_offset..bytes.len()).step_by(packet_len).take(4);
+            syncs.clone().next().is_some() && syncs.all(|offset| bytes[offset] == SYNC_BYTE)
+        })
+}
+
+/// Iterates over the transport packets of a stream, stopping after the first error.
+fn packets(bytes: &[u8]) -> Result<impl Iterator<Item = Result<Packet<'_>, Error>>, Error> {
+    let (packet_len, sync_offset) = match packet_layout(bytes) {
+        Some(layout) => layout,
+        None if bytes.is_empty() => (TS_PACKET_LEN, 0),
+        None => return Err(Error::new(ErrorKind::TsSyncLost).at_offset(0)),
+    };
+
+    let mut failed = false;
+
+    Ok(bytes
+        .chunks(packet_len)
+        .enumerate()
+        .map_while(move |(index, packet)| {
+            if failed {
+                return None;
+            }
+
+            let offset = index * packet_len + sync_offset;
+            let packet = parse_packet(&packet[sync_offset.min(packet.len())..], offset)
+                .map_err(|err| err.at_offset(offset));
+            failed = packet.is_err();
+
+            Some(packet)
+        })
+        .filter_map(Result::transpose))
+}
+
+/// Parses one transport packet, returning `None` for packets without a usable payload.
+fn parse_packet(packet: &[u8], offset: usize) -> Result<Option<Packet<'_>>, Error> {
+    let Some(header) = packet.first_chunk::<4>() else {
+        return Err(Error::new(ErrorKind::Truncated {
+            field: "transport packet",
+        }));
+    };
+
+    if header[0] != SYNC_BYTE {
+        return Err(Error::new(ErrorKind::TsSyncLost));
+    }
+
+    if packet.len() < TS_PACKET_LEN {
+        return Err(Error::new(ErrorKind::Truncated {
+            field: "transport packet",
+        }));
+    }
+
+    let transport_error = header[1] & 0x80 != 0;
+    let unit_start = header[1] & 0x40 != 0;
+    let pid = u16::from_be_bytes([header[1], header[2]]) & 0x1FFF;
+    let adaptation_field = header[3] & 0x20 != 0;
+    let has_payload = header[3] & 0x10 != 0;
+    let continuity_counter = header[3] & 0x0F;
+
+    if transport_error {
+        tracing::warn!("skipping transport packet with error indicator at byte offset {offset}");
+        return Ok(None);
+    }
+
+    let mut payload = &packet[4..TS_PACKET_LEN];
+
+    if adaptation_field {
+        let len = usize::from(payload[0]);
+
+        payload = payload.get(1 + len..).ok_or_else(|| {
+            Error::new(ErrorKind::InvalidLength {
+                field: "transport packet adaptation field",
+                expected: payload.len() - 1,
+                found: len,
+            })
+            .at_position(4)
+        })?;
+    }
+
+    if !has_payload {
+        return Ok(None);
+    }
+
+    Ok(Some(Packet {
+        offset,
+        pid,
+        unit_start,
+        continuity_counter,
+        payload,
+    }))
+}
+
+/// Program association and program map tables, collected until every PMT has been seen.
+#[derive(Debug, Default)]
+struct ProgramTables {
+    /// PMT PIDs by program number, once the PAT has been seen.
+    pmt_pids: Option<HashMap<u16, u16>>,
+    /// Programs whose PMT has been seen.
+    seen_programs: Vec<u16>,
+    sections: HashMap<u16, Vec<u8>>,
+    streams: Vec<PgsStream>,
+}
+
+impl ProgramTables {
+    /// Reads any table section in the packet, returning whether every table has been seen.
+    fn push(&mut self, packet: &Packet<'_>) -> bool {
+        let is_table = packet.pid == PAT_PID
+            || self
+                .pmt_pids
+                .iter()
+                .flat_map(HashMap::values)
+                .any(|&pid| pid == packet.pid);
+
+        if !is_table {
+            return false;
+        }
+
+        let Some(section) = self.reassemble(packet) else {
+            return false;
+        };
+
+        match section.first() {
+            Some(0x00) if self.pmt_pids.is_none() => {
+                self.pmt_pids = Some(parse_pat(&section));
+            }
+            Some(0x02) => {
+                if let Some((program_number, streams)) = parse_pmt(&section)
+                    && !self.seen_programs.contains(&program_number)
+                {
+                    self.seen_programs.push(program_number);
+                    self.streams.extend(streams);
+                }
+            }
+            _ => {}
+        }
+
+        self.pmt_pids.as_ref().is_some_and(|pmt_pids| {
+            pmt_pids
+                .keys()
+                .all(|program| self.seen_programs.contains(program))
+        })
+    }
+
+    /// Collects the section starting in a unit start packet, returning it once complete.
+    fn reassemble(&mut self, packet: &Packet<'_>) -> Option<Vec<u8>> {
+        let buf = self.sections.entry(packet.pid).or_default();
+
+        if packet.unit_start {
+            let pointer = usize::from(*packet.payload.first()?);
+            buf.clear();
+            buf.extend_from_slice(packet.payload.get(1 + pointer..)?);
+        } else if !buf.is_empty() {
+            buf.extend_from_slice(packet.payload);
+        }
+
+        let section_len =
+            3 + usize::from(u16::from_be_bytes([*buf.get(1)?, *buf.get(2)?]) & 0x0FFF);
+
+        if buf.len() < section_len {
+            return None;
+        }
+
+        let mut section = std::mem::take(buf);
+        section.truncate(section_len);
+        Some(section)
+    }
+}
+
+/// Reads the PMT PID of every program from a PAT section.
+fn parse_pat(section: &[u8]) -> HashMap<u16, u16> {
+    // 8 bytes of header before the programs, 4 bytes of CRC after them
+    let programs = section
+        .get(8..section.len().saturating_sub(4))
+        .unwrap_or_default();
+
+    programs
+        .chunks_exact(4)
+        .map(|program| {
+            (
+                u16::from_be_bytes([program[0], program[1]]),
+                u16::from_be_bytes([program[2], program[3]]) & 0x1FFF,
+            )
+        })
+        // program 0 points at the network information table
+        .filter(|&(program_number, _)| program_number != 0)
+        .collect()
+}
+
+/// Reads the program number and PGS streams of a PMT section.
+fn parse_pmt(section: &[u8]) -> Option<(u16, Vec<PgsStream>)> {
+    let program_number = u16::from_be_bytes([*section.get(3)?, *section.get(4)?]);
+    let program_info_len =
+        usize::from(u16::from_be_bytes([*section.get(10)?, *section.get(11)?]) & 0x0FFF);
+
+    let mut streams = Vec::new();
+    let mut entries = section.get(12 + program_info_len..section.len().checked_sub(4)?)?;
+
+    while let [stream_type, pid_hi, pid_lo, info_hi, info_lo, rest @ ..] = entries {
+        let pid = u16::from_be_bytes([*pid_hi, *pid_lo]) & 0x1FFF;
+        let info_len = usize::from(u16::from_be_bytes([*info_hi, *info_lo]) & 0x0FFF);
+        let descriptors = rest.get(..info_len)?;
+        entries = &rest[info_len..];
+
+        if *stream_type == PGS_STREAM_TYPE {
+            streams.push(PgsStream {
+                pid,
+                program_number: Some(program_number),
+                language: find_language(descriptors),
+            });
+        }
+    }
+
+    Some((program_number, streams))
+}
+
+fn find_language(mut descriptors: &[u8]) -> Option<String> {
+    while let [tag, len, rest @ ..] = descriptors {
+        let body = rest.get(..usize::from(*len))?;
+
+        if *tag == LANGUAGE_DESCRIPTOR_TAG && body.len() >= 3 {
+            return Some(String::from_utf8_lossy(&body[..3]).into_owned());
+        }
+
+        descriptors = &rest[body.len()..];
+    }
+
+    None
+}
+
+/// A complete PES packet and the offset of the transport packet it started in.
+#[derive(Debug)]
+struct Pes {
+    offset: usize,
+    data: Vec<u8>,
+}
+
+/// Collects the payloads of one PID's transport packets into PES packets.
+#[derive(Debug, Default)]
+struct PesAssembler {
+    pending: Option<Pes>,
+    continuity_counter: Option<u8>,
+}
+
+impl PesAssembler {
+    /// Adds a packet, returning the previous PES packet if this one starts a new one.
+    fn push(&mut self, packet: &Packet<'_>) -> Option<Pes> {
+        let previous = self.continuity_counter.replace(packet.continuity_counter);
+
+        if let Some(previous) = previous {
+            // a duplicate packet repeats the previous counter and carries nothing new
+            if packet.continuity_counter == previous {
+                return None;
+            }
+
+            if packet.continuity_counter != (previous + 1) & 0x0F
+                && let Some(pending) = self.pending.take()
+            {
+                tracing::warn!(
+                    "dropping PES packet at byte offset {} after lost transport packets",
+                    pending.offset,
+                );
+            }
+        }
+
+        if packet.unit_start {
+            return self.pending.replace(Pes {
+                offset: packet.offset,
+                data: packet.payload.to_vec(),
+            });
+        }
+
+        if let Some(pending) = &mut self.pending {
+            pending.data.extend_from_slice(packet.payload);
+        }
+
+        None
+    }
+
+    fn finish(&mut self) -> Option<Pes> {
+        self.pending.take()
+    }
+}
+
+/// Bytes of a PES header before its optional fields.
+const PES_HEADER_LEN: usize = 9;
+
+impl Pes {
+    /// Passes each segment carried by this PES packet to `sink`.
+    fn segments(
+        &self,
+        mut sink: impl FnMut(Result<Segment, Error>) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let data = &self.data;
+
+        let Some(header) = data.first_chunk::<PES_HEADER_LEN>() else {
+            return Err(Error::new(ErrorKind::Truncated {
+                field: "PES header",
+            }));
+        };
+
+        if let Some(&value) = header[..3]
+            .iter()
+            .zip([0, 0, 1])
+            .find_map(|(byte, expected)| (*byte != expected).then_some(byte))
+        {
+            return Err(Error::new(ErrorKind::InvalidFlag {
+                field: "PES start code",
+                value,
+            }));
+        }
+
+        let packet_len = usize::from(u16::from_be_bytes([header[4], header[5]]));
+        let timestamp_flags = header[7] >> 6;
+        let optional_len = usize::from(header[8]);
+
+        let end = match packet_len {
+            // unbounded, as allowed for video only but harmless here
+            0 => data.len(),
+            len => 6 + len,
+        };
+
+        let (Some(optional), Some(mut payload)) = (
+            data.get(PES_HEADER_LEN..PES_HEADER_LEN + optional_len),
+            data.get(PES_HEADER_LEN + optional_len..end),
+        ) else {
+            return Err(Error::new(ErrorKind::Truncated {
+                field: "PES packet",
+            }));
+        };
+
+        let timestamp = |index: usize| {
+            optional
+                .get(5 * index..5 * index + 5)
+                .map(parse_timestamp)
+                .ok_or_else(|| {
+                    Error::new(ErrorKind::Truncated {
+                        field: "PES timestamps",
+                    })
+                })
+        };
+
+        let (pts, dts) = match timestamp_flags {
+            0b10 => {
+                let pts = timestamp(0)?;
+                (pts, pts)
+            }
+            0b11 => (timestamp(0)?, timestamp(1)?),
+            // every PGS segment needs a presentation time, and 0b01 is forbidden
+            value => {
+                return Err(Error::new(ErrorKind::InvalidFlag {
+                    field: "PES timestamp flags",
+                    value,
+                })
+                .at_position(7));
+            }
+        };
+
+        while !payload.is_empty() {
+            let start = data.len() - payload.len();
+
+            let segment = split_segment(&mut payload)
+                .map_err(|err| err.at_position(start))
+                .and_then(|(segment_type, seg_data)| {
+                    decode_payload(segment_type, seg_data)
+                        .map(|data| Segment { pts, dts, data })
+                        .map_err(|err| {
+                            // payload positions count from the end of a `.sup` segment header,
+                            // but here the payload follows a 3-byte header at `start`
+                            let position = start + 3 + err.position().saturating_sub(HEADER_LEN);
+                            err.at_position(position)
+                                .with_segment_type(segment_type)
+                                .with_pts(pts)
+                        })
+                });
+
+            match segment {
+                Ok(segment) => sink(Ok(segment)).map_err(|err| err.at_position(start))?,
+                Err(err) => {
+                    sink(Err(err))?;
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Splits a segment, which in a PES packet has no magic number or timestamps, off the front of
+/// `payload`.
+fn split_segment<'a>(payload: &mut &'a [u8]) -> Result<(SegmentType, &'a [u8]), Error> {
+    let Some((&[type_byte, len_hi, len_lo], rest)) = payload.split_first_chunk::<3>() else {
+        return Err(Error::new(ErrorKind::Truncated {
+            field: "PES segment header",
+        }));
+    };
+
+    let segment_type = SegmentType::from_byte(type_byte)
+        .ok_or_else(|| Error::new(ErrorKind::UnknownSegmentType(type_byte)))?;
+
+    let len = usize::from(u16::from_be_bytes([len_hi, len_lo]));
+    let Some(seg_data) = rest.get(..len) else {
+        return Err(Error::new(ErrorKind::Truncated {
+            field: "PGS segment payload",
+        })
+        .with_segment_type(segment_type));
+    };
+
+    *payload = &rest[len..];
+
+    Ok((segment_type, seg_data))
+}
+
+/// Decodes a 33-bit PTS or DTS spread over 5 bytes with marker bits.
+fn parse_timestamp(bytes: &[u8]) -> Timestamp {
+    let ticks = (u64::from(bytes[0] >> 1 & 0x07) << 30)
+        | (u64::from(bytes[1]) << 22)
+        | (u64::from(bytes[2] >> 1) << 15)
+        | (u64::from(bytes[3]) << 7)
+        | u64::from(bytes[4] >> 1);
+
+    Timestamp::from_ticks(ticks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SegmentData;
+
+    const PMT_PID: u16 = 0x0100;
+
+    /// A segment of a `.sup` file without its "PG" header, as carried in a PES packet.
+    struct SupSegment<'a> {
+        pts: u64,
+        dts: u64,
+        body: &'a [u8],
+    }
+
+    fn sup_segments(mut sup: &[u8]) -> Vec<SupSegment<'_>> {
+        let mut segments = Vec::new();
+
+        while !sup.is_empty() {
+            let len = 13 + usize::from(u16::from_be_bytes([sup[11], sup[12]]));
+
+            segments.push(SupSegment {
+                pts: u64::from(u32::from_be_bytes(sup[2..6].try_into().unwrap())),
+                dts: u64::from(u32::from_be_bytes(sup[6..10].try_into().unwrap())),
+                body: &sup[10..len],
+            });
+
+            sup = &sup[len..];
+        }
+
+        segments
+    }
+
+    fn encode_timestamp(prefix: u8, ticks: u64) -> [u8; 5] {
+        [
+            prefix << 4 | (ticks >> 29) as u8 & 0x0E | 1,
+            (ticks >> 22) as u8,
+            (ticks >> 14) as u8 | 1,
+            (ticks >> 7) as u8,
+            (ticks << 1) as u8 | 1,
+        ]
+    }
+
+    fn pes(segment: &SupSegment<'_>) -> Vec<u8> {
+        let mut pes = vec![0, 0, 1, 0xBD, 0, 0, 0x81, 0xC0, 10];
+        pes.extend(encode_timestamp(0b0011, segment.pts));
+        pes.extend(encode_timestamp(0b0001, segment.dts));
+        pes.extend(segment.body);
+
+        let len = (pes.len() - 6) as u16;
+        pes[4..6].copy_from_slice(&len.to_be_bytes());
+        pes
+    }
+
+    /// Test muxer, which splits units into packets and pads the last one with an adaptation field.
+    #[derive(Default)]
+    struct Muxer {
+        bdav: bool,
+        counters: HashMap<u16, u8>,
+        out: Vec<u8>,
+    }
+
+    impl Muxer {
+        fn write(&mut self, pid: u16, mut unit: &[u8]) {
+            let mut unit_start = true;
+
+            while !unit.is_empty() {
+                let len = unit.len().min(184);
+                let counter = self.counters.entry(pid).or_default();
+
+                if self.bdav {
+                    self.out.extend([0; 4]);
+                }
+
+                let [pid_hi, pid_lo] = pid.to_be_bytes();
+                self.out.extend([
+                    SYNC_BYTE,
+                    u8::from(unit_start) << 6 | pid_hi,
+                    pid_lo,
+                    if len < 184 { 0x30 } else { 0x10 } | *counter,
+                ]);
+
+                if len < 184 {
+                    let stuffing = 183 - len;
+                    self.out.push(stuffing as u8);
+                    if stuffing > 0 {
+                        self.out.push(0);
+                        self.out.extend(std::iter::repeat_n(0xFF, stuffing - 1));
+                    }
+                }
+
+                self.out.extend(&unit[..len]);
+                unit = &unit[len..];
+                unit_start = false;
+                *counter = (*counter + 1) & 0x0F;
+            }
+        }
+
+        fn write_section(&mut self, pid: u16, table_id: u8, id: u16, body: &[u8]) {
+            let len = (5 + body.len() + 4) as u16;
+            let mut section = vec![0, table_id, 0xB0 | (len >> 8) as u8, len as u8];
+            section.extend(id.to_be_bytes());
+            section.extend([0xC1, 0, 0]);
+            section.extend(body);
+            // the CRC is not checked
+            section.extend([0; 4]);
+
+            self.write(pid, &section);
+        }
+
+        fn write_tables(&mut self, streams: &[(u16, &str)]) {
+            let [pmt_hi, pmt_lo] = PMT_PID.to_be_bytes();
+            self.write_section(PAT_PID, 0x00, 1, &[0, 1, 0xE0 | pmt_hi, pmt_lo]);
+
+            let mut pmt = vec![0xFF, 0xFF, 0xF0, 0];
+            for (pid, language) in streams {
+                let [pid_hi, pid_lo] = pid.to_be_bytes();
+                pmt.extend([PGS_STREAM_TYPE, 0xE0 | pid_hi, pid_lo, 0xF0, 6]);
+                pmt.extend([LANGUAGE_DESCRIPTOR_TAG, 4]);
+                pmt.extend(language.as_bytes());
+                pmt.push(0);
+            }
+            self.write_section(PMT_PID, 0x02, 1, &pmt);
+        }
+
+        fn write_sup(&mut self, pid: u16, sup: &[u8]) {
+            for segment in sup_segments(sup) {
+                self.write(pid, &pes(&segment));
+            }
+        }
+    }
+
+    fn mux(bdav: bool, streams: &[(u16, &str, &[u8])]) -> Vec<u8> {
+        let mut muxer = Muxer {
+            bdav,
+            ..Muxer::default()
+        };
+
+        let tables = streams
+            .iter()
+            .map(|&(pid, language, _)| (pid, language))
+            .collect::<Vec<_>>();
+        muxer.write_tables(&tables);
+
+        for &(pid, _, sup) in streams {
+            muxer.write_sup(pid, sup);
+        }
+
+        muxer.out
+    }
+
+    #[test]
+    fn demuxes_bdav_packets() {
+        let sup = std::fs::read("data/small.sup").unwrap();
+        let m2ts = mux(true, &[(0x1200, "eng", &sup)]);
+        assert_eq!(0, m2ts.len() % BDAV_PACKET_LEN);
+
+        assert_eq!(
+            crate::parse_frames(&sup).unwrap(),
+            parse_frames(&m2ts, 0x1200).unwrap(),
+        );
+    }
+
+    #[test]
+    fn tells_transport_streams_from_sup_files() {
+        let sup = std::fs::read("data/small.sup").unwrap();
+        assert!(!is_transport_stream(&sup[..PROBE_LEN]));
+
+        for bdav in [true, false] {
+            let ts = mux(bdav, &[(0x1200, "eng", &sup)]);
+            assert!(is_transport_stream(&ts[..PROBE_LEN]));
+        }
+    }
+
+    #[test]
+    fn demuxes_plain_transport_packets() {
+        let sup = std::fs::read("data/mummyforced.sup").unwrap();
+        let ts = mux(false, &[(0x1200, "eng", &sup)]);
+        assert_eq!(0, ts.len() % TS_PACKET_LEN);
+
+        let mut expected = Vec::new();
+        let mut input = sup.as_slice();
+        while !input.is_empty() {
+            expected.push(crate::parse_segment(&mut input).unwrap());
+        }
+
+        assert_eq!(expected, segments(&ts, 0x1200).unwrap());
+    }
+
+    #[test]
+    fn lists_and_picks_streams() {
+        let small = std::fs::read("data/small.sup").unwrap();
+        let mummy = std::fs::read("data/mummyforced.sup").unwrap();
+        let m2ts = mux(true, &[(0x1201, "fra", &mummy), (0x1200, "eng", &small)]);
+
+        assert_eq!(
+            vec![
+                PgsStream {
+                    pid: 0x1200,
+                    program_number: Some(1),
+                    language: Some("eng".to_owned()),
+                },
+                PgsStream {
+                    pid: 0x1201,
+                    program_number: Some(1),
+                    language: Some("fra".to_owned()),
+                },
+            ],
+            pgs_streams(&m2ts).unwrap(),
+        );
+
+        assert_eq!(
+            crate::parse_frames(&mummy).unwrap(),
+            parse_frames(&m2ts, 0x1201).unwrap(),
+        );
+        assert!(parse_frames(&m2ts, 0x1202).unwrap().is_empty());
+    }
+
+    #[test]
+    fn falls_back_to_pgs_pids_without_tables() {
+        let sup = std::fs::read("data/small.sup").unwrap();
+
+        let mut muxer = Muxer::default();
+        muxer.write_sup(0x1203, &sup);
+
+        assert_eq!(
+            vec![PgsStream {
+                pid: 0x1203,
+                program_number: None,
+                language: None,
+            }],
+            pgs_streams(&muxer.out).unwrap(),
+        );
+    }
+
+    #[test]
+    fn keeps_33_bit_timestamps() {
+        let pts = 0x1_2345_6789;
+        let dts = 0x1_2345_0000;
+
+        let mut muxer = Muxer::default();
+        muxer.write(
+            0x1200,
+            &pes(&SupSegment {
+                pts,
+                dts,
+                body: &[0x80, 0, 0],
+            }),
+        );
+
+        let segments = segments(&muxer.out, 0x1200).unwrap();
+        assert_eq!(1, segments.len());
+        assert_eq!(Timestamp::from_ticks(pts), segments[0].pts);
+        assert_eq!(Timestamp::from_ticks(dts), segments[0].dts);
+        assert_eq!(SegmentData::End, segments[0].data);
+    }
+
+    #[test]
+    fn drops_pes_packets_with_lost_transport_packets() {
+        let sup = std::fs::read("data/mummyforced.sup").unwrap();
+        let ts = mux(false, &[(0x1200, "eng", &sup)]);
+        let all = segments(&ts, 0x1200).unwrap();
+
+        // the first packet that continues a PES packet, rather than starting one
+        let lost = ts
+            .chunks(TS_PACKET_LEN)
+            .position(|packet| packet[1] & 0x40 == 0)
+            .unwrap();
+        let mut damaged = ts.clone();
+        damaged.drain(lost * TS_PACKET_LEN..(lost + 1) * TS_PACKET_LEN);
+
+        assert_eq!(all.len() - 1, segments(&damaged, 0x1200).unwrap().len());
+    }
+
+    #[test]
+    fn reports_lost_sync() {
+        let sup = std::fs::read("data/small.sup").unwrap();
+        let mut m2ts = mux(true, &[(0x1200, "eng", &sup)]);
+        m2ts[5 * BDAV_PACKET_LEN + 4] = 0;
+
+        let err = parse_frames(&m2ts, 0x1200).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::TsSyncLost));
+        assert_eq!(Some(5 * BDAV_PACKET_LEN + 4), err.offset());
+
+        let err = pgs_streams(&m2ts[..BDAV_PACKET_LEN - 1]).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::Truncated { .. }));
+    }
+
+    #[test]
+    fn locates_segment_errors() {
+        let mut muxer = Muxer::default();
+        muxer.write(
+            0x1200,
+            &pes(&SupSegment {
+                pts: 90,
+                dts: 0,
+                body: &[0x80, 0, 1, 0],
+            }),
+        );
+
+        let err = segments(&muxer.out, 0x1200).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::TrailingData { len: 1 }));
+        assert_eq!(Some(SegmentType::END), err.segment_type());
+        assert_eq!(Some(Timestamp::from_ticks(90)), err.pts());
+        assert_eq!(Some(0), err.segment_index());
+        assert_eq!(Some(0), err.segment_offset());
+        // the 9-byte PES header and 10 bytes of timestamps, the 3-byte END header, then the
+        // stray byte
+        assert_eq!(Some(22), err.offset());
+    }
+
+    #[test]
+    fn rejects_pes_packets_without_pts() {
+        for flags in [0b00, 0b01] {
+            let mut pes = vec![0, 0, 1, 0xBD, 0, 6, 0x81, flags << 6, 0, 0x80, 0, 0];
+            if flags == 0b01 {
+                pes[8] = 5;
+                pes.splice(9..9, encode_timestamp(0b0001, 90));
+                pes[5] += 5;
+            }
+
+            let mut muxer = Muxer::default();
+            muxer.write(0x1200, &pes);
+
+            let err = segments(&muxer.out, 0x1200).unwrap_err();
+            assert!(matches!(
+                err.kind(),
+                ErrorKind::InvalidFlag {
+                    field: "PES timestamp flags",
+                    value,
+                } if *value == flags,
+            ));
+            assert_eq!(Some(7), err.offset());
+        }
+    }
+}
@@ -1,12 +1,14 @@
+use std::sync::Arc;
+
 use iced::{
     Alignment, Color, Element, Length, Point, Renderer, Size, Task, Theme,
     mouse::Cursor,
     widget::{Canvas, Container, Row, button, canvas, checkbox, column, text},
 };
 
-use crate::{
-    DisplaySet,
-    ocr::{OcrFrame, OcrState},
+use sup_decode::{
+    BitmapCache, DisplaySet, DisplayUpdate, Rect, Timestamp,
+    ocr::{self, OcrEngine, OcrFrame, OcrState},
 };
 
 const TRANSPARENT: [f32; 4] = [0.0, 0.0, 0.0, 0.0];
@@ -22,23 +24,45 @@ pub(crate) enum Message {
 
 #[derive(Debug)]
 pub(crate) struct SupViewer {
-    frames: Vec<DisplaySet>,
-    ocr_frames: Vec<OcrFrame>,
+    frames: Arc<[DisplaySet]>,
+    ocr_engine: Box<dyn OcrEngine>,
+    /// OCR results of the frames shown so far; frames are only recognised once they are shown.
+    ocr_frames: Vec<Option<OcrFrame>>,
+    /// Decoded bitmaps of recently drawn objects; the rest stay run-length encoded.
+    bitmaps: BitmapCache,
     current_frame: usize,
     show_outlines: bool,
 }
 
 impl SupViewer {
-    pub(crate) fn new(frames: Vec<DisplaySet>, ocr_frames: Vec<OcrFrame>) -> (Self, Task<Message>) {
-        (
-            Self {
-                frames,
-                ocr_frames,
-                current_frame: 0,
-                show_outlines: true,
-            },
-            Task::none(),
-        )
+    pub(crate) fn new(
+        frames: Arc<[DisplaySet]>,
+        ocr_engine: Box<dyn OcrEngine>,
+    ) -> (Self, Task<Message>) {
+        let mut viewer = Self {
+            ocr_frames: vec![None; frames.len()],
+            frames,
+            ocr_engine,
+            bitmaps: BitmapCache::default(),
+            current_frame: 0,
+            show_outlines: true,
+        };
+        viewer.recognize_current_frame();
+
+        (viewer, Task::none())
+    }
+
+    /// Runs OCR on the frame being shown unless it has been recognised before.
+    fn recognize_current_frame(&mut self) {
+        let Some(ocr_frame @ None) = self.ocr_frames.get_mut(self.current_frame) else {
+            return;
+        };
+
+        *ocr_frame = Some(ocr::recognize_frame(
+            &mut *self.ocr_engine,
+            &self.frames[self.current_frame],
+            &self.bitmaps,
+        ));
     }
 
     pub(crate) fn view(&self) -> Element<'_, Message> {
@@ -52,7 +76,9 @@ impl SupViewer {
         }
 
         let ds = &self.frames[self.current_frame];
-        let ocr = &self.ocr_frames[self.current_frame];
+        let ocr = self.ocr_frames[self.current_frame]
+            .as_ref()
+            .expect("frames are recognised when they are shown");
 
         let canvas = Canvas::new(self)
             .width(Length::FillPortion(3))
@@ -103,13 +129,20 @@ impl SupViewer {
 
         let content = column![
             text(format!(
-                "frame {} / {}  video={}x{}  object={}x{}",
+                "frame {} / {}  update={:?}  palette={}  video={}x{}  objects={}",
                 self.current_frame + 1,
                 self.frames.len(),
+                ds.update,
+                ds.pds.id,
                 ds.pcs.width,
                 ds.pcs.height,
-                ds.ods.width,
-                ds.ods.height
+                ds.placements()
+                    .map(|placement| format!(
+                        "{}x{}",
+                        placement.object.width, placement.object.height
+                    ))
+                    .collect::<Vec<_>>()
+                    .join(", "),
             )),
             Row::new()
                 .spacing(12)
@@ -151,6 +184,8 @@ impl SupViewer {
                 self.current_frame += 1;
             }
         }
+
+        self.recognize_current_frame();
     }
 }
 
@@ -194,22 +229,22 @@ impl canvas::Program<Message> for Timeline<'_> {
 
         let start = self.frames.first().unwrap().pts;
         let end = self.frames.last().unwrap().pts;
-        let total_ms = end.signed_duration_since(start).num_milliseconds();
+        let total_ticks = end.ticks().saturating_sub(start.ticks());
 
         for (index, display_set) in self.frames.iter().enumerate() {
-            let x = if total_ms <= 0 {
+            let x = if total_ticks == 0 {
                 start_x
             } else {
-                let elapsed = display_set
-                    .pts
-                    .signed_duration_since(start)
-                    .num_milliseconds() as f32;
-                let progress = (elapsed / total_ms as f32).clamp(0.0, 1.0);
+                let elapsed = display_set.pts.ticks().saturating_sub(start.ticks()) as f32;
+                let progress = (elapsed / total_ticks as f32).clamp(0.0, 1.0);
                 start_x + (end_x - start_x) * progress
             };
 
             let (height, width, color) = if index == self.current_frame {
                 (18.0, 2.5, Color::from_rgb(1.0, 0.25, 0.25))
+            } else if display_set.update == DisplayUpdate::PaletteOnly {
+                // palette-only updates are drawn shorter so fades don't drown out compositions
+                (6.0, 1.0, Color::from_rgb(1.0, 0.85, 0.3))
             } else {
                 (10.0, 1.0, Color::WHITE)
             };
@@ -284,66 +319,71 @@ impl canvas::Program<Message> for SupViewer {
                 .with_width(1.0),
         );
 
-        let ods = &ds.ods;
-        let obj = ds.pcs.find_object_by_id(ods.id).unwrap();
-
-        // Draw the object bounding box in red to make obvious misalignment visible.
-        if self.show_outlines {
-            let object_box = canvas::Path::rectangle(
+        let screen_rect = |rect: Rect| {
+            canvas::Path::rectangle(
                 Point::new(
-                    offset_x + obj.x as f32 * scale,
-                    offset_y + obj.y as f32 * scale,
+                    offset_x + rect.x as f32 * scale,
+                    offset_y + rect.y as f32 * scale,
                 ),
-                Size::new(ods.width as f32 * scale, ods.height as f32 * scale),
-            );
-            frame.stroke(
-                &object_box,
-                canvas::Stroke::default()
-                    .with_color(Color::from_rgb(1.0, 0.2, 0.2))
-                    .with_width(1.0),
-            );
-        }
-
-        let data = &ods.data;
-        let w = ods.width as usize;
+                Size::new(rect.width as f32 * scale, rect.height as f32 * scale),
+            )
+        };
 
-        for (i, color_id) in data.iter().enumerate() {
-            if *color_id == 0 {
-                continue;
+        // Draw window boundaries in blue; objects are clipped to these.
+        if self.show_outlines {
+            for window in &ds.wds {
+                frame.stroke(
+                    &screen_rect(window.rect()),
+                    canvas::Stroke::default()
+                        .with_color(Color::from_rgb(0.3, 0.5, 1.0))
+                        .with_width(1.0),
+                );
             }
+        }
 
-            let x = (i % w) as f32;
-            let y = (i / w) as f32;
-
-            let color = ds
-                .pds
-                .find_by_id(*color_id)
-                .map(|y_cr_cb| y_cr_cb.rgba())
-                .unwrap_or(TRANSPARENT);
-
-            let pixel = canvas::Path::rectangle(
-                Point::new(
-                    offset_x + (obj.x as f32 + x) * scale,
-                    offset_y + (obj.y as f32 + y) * scale,
-                ),
-                Size::new(scale.max(1.0), scale.max(1.0)),
-            );
+        for placement in ds.placements() {
+            // Draw the object bounding box in red to make obvious misalignment visible.
+            if self.show_outlines {
+                frame.stroke(
+                    &screen_rect(placement.object_rect()),
+                    canvas::Stroke::default()
+                        .with_color(Color::from_rgb(1.0, 0.2, 0.2))
+                        .with_width(1.0),
+                );
+            }
 
-            frame.fill(
-                &pixel,
-                canvas::Fill {
-                    style: canvas::Style::Solid(Color::from(color)),
-                    rule: canvas::fill::Rule::NonZero,
-                },
-            );
+            for (x, y, color_id) in placement.pixels(&self.bitmaps) {
+                if color_id == 0 {
+                    continue;
+                }
+
+                let color = ds
+                    .pds
+                    .find_by_id(color_id)
+                    .map(|y_cr_cb| y_cr_cb.rgba())
+                    .unwrap_or(TRANSPARENT);
+
+                let pixel = canvas::Path::rectangle(
+                    Point::new(offset_x + x as f32 * scale, offset_y + y as f32 * scale),
+                    Size::new(scale.max(1.0), scale.max(1.0)),
+                );
+
+                frame.fill(
+                    &pixel,
+                    canvas::Fill {
+                        style: canvas::Style::Solid(Color::from(color)),
+                        rule: canvas::fill::Rule::NonZero,
+                    },
+                );
+            }
         }
 
         vec![frame.into_geometry()]
     }
 }
 
-fn format_timestamp(pts: chrono::NaiveTime) -> String {
-    pts.format("%H:%M:%S%.3f").to_string()
+fn format_timestamp(pts: Timestamp) -> String {
+    pts.to_string()
 }
 
 fn render_ocr_panel(ocr: &OcrFrame) -> Element<'_, Message> {
@@ -355,6 +395,7 @@ fn render_ocr_panel(ocr: &OcrFrame) -> Element<'_, Message> {
             ocr.subtitle_size.0, ocr.subtitle_size.1
         )),
         text(format!("pts: {}", format_timestamp(ocr.pts))),
+        text(format!("forced: {}", if ocr.forced { "yes" } else { "no" })),
     ]
     .spacing(4);
 
@@ -0,0 +1,117 @@
+//! The checks behind the fuzz targets in `fuzz/`, run on generated inputs so that `cargo test`
+//! covers them without a nightly toolchain.
+//!
+//! This is an integration test rather than a unit test because the allocation checks need their
+//! own global allocator.
+
+#[path = "../fuzz/src/lib.rs"]
+mod checks;
+
+use proptest::prelude::*;
+
+#[global_allocator]
+static ALLOC: checks::TrackingAlloc = checks::TrackingAlloc;
+
+/// A sample stream with a few bytes overwritten and possibly cut short, which reaches much deeper
+/// into the decoder than random bytes do.
+fn damaged_sample() -> impl Strategy<Value = Vec<u8>> {
+    let sample = prop_oneof![
+        Just(std::fs::read("data/small.sup").unwrap()),
+        Just(std::fs::read("data/mummyforced.sup").unwrap()),
+    ];
+
+    sample.prop_flat_map(|sample| {
+        let len = sample.len();
+        let edits = prop::collection::vec((0..len, any::<u8>()), 0..8);
+
+        (Just(sample), edits, len / 2..=len).prop_map(|(mut sample, edits, keep)| {
+            for (index, byte) in edits {
+                sample[index] = byte;
+            }
+
+            sample.truncate(keep);
+            sample
+        })
+    })
+}
+
+/// A few transport packets on PGS and table PIDs with random payloads, which random bytes would
+/// almost never form.
+fn transport_packets() -> impl Strategy<Value = Vec<u8>> {
+    let pid = prop_oneof![Just(0x0000u16), Just(0x0100), Just(0x1200)];
+    let packet = (
+        pid,
+        any::<bool>(),
+        0..16u8,
+        prop::collection::vec(any::<u8>(), 184),
+    );
+
+    prop::collection::vec(packet, 1..16).prop_map(|packets| {
+        packets
+            .into_iter()
+            .flat_map(|(pid, unit_start, counter, payload)| {
+                let [pid_hi, pid_lo] = pid.to_be_bytes();
+                let mut packet = vec![0x47, u8::from(unit_start) << 6 | pid_hi, pid_lo];
+                packet.push(0x10 | counter);
+                packet.extend(payload);
+                packet
+            })
+            .collect()
+    })
+}
+
+proptest! {
+    #[test]
+    fn parse_segment(data in prop::collection::vec(any::<u8>(), 0..512)) {
+        checks::parse_segment(&data);
+    }
+
+    #[test]
+    fn parse_segment_of_damaged_sample(data in damaged_sample()) {
+        checks::parse_segment(&data);
+    }
+
+    #[test]
+    fn parse_frames(data in prop::collection::vec(any::<u8>(), 0..512)) {
+        checks::parse_frames(&data);
+    }
+
+    #[test]
+    fn parse_frames_of_damaged_sample(data in damaged_sample()) {
+        checks::parse_frames(&data);
+    }
+
+    #[test]
+    fn demux(data in transport_packets()) {
+        checks::demux(&data);
+    }
+
+    #[test]
+    fn decode_rle(data in prop::collection::vec(any::<u8>(), 0..512)) {
+        checks::decode_rle(&data);
+    }
+
+    #[test]
+    fn rle_round_trip(data in prop::collection::vec(any::<u8>(), 0..512)) {
+        checks::rle_round_trip(&data);
+    }
+}
+
+#[test]
+fn object_data_length_reserves_nothing_up_front() {
+    // the first fragment of an object claiming 16 MiB of RLE data, of which 1 byte arrives
+    let mut data = vec![0x50, 0x47, 0, 0, 0, 0, 0, 0, 0, 0, 0x15, 0, 12];
+    data.extend([0, 0, 0, 0x80, 0xFF, 0xFF, 0xFF, 0x10, 0, 0x10, 0, 1]);
+
+    checks::parse_segment(&data);
+    checks::parse_frames(&data);
+}
+
+#[test]
+fn every_sample_passes_checks() {
+    for path in ["data/small.sup", "data/mummyforced.sup"] {
+        let data = std::fs::read(path).unwrap();
+        checks::parse_segment(&data);
+        checks::parse_frames(&data);
+    }
+}